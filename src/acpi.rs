@@ -0,0 +1,456 @@
+use core::mem::size_of;
+
+use crate::multiboot::{self, AcpiNewRspd, AcpiOldRspd, Tag};
+use crate::paging;
+
+
+/// Header common to all ACPI System Description Tables.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the whole table, including the header. Length reported by firmware is clamped to
+    /// memory, which is actually mapped.
+    pub fn bytes(&'static self) -> &'static [u8] {
+        let ptr = (self as *const SdtHeader).cast::<u8>();
+        let len = paging::mapped_length(x86_64::VirtAddr::from_ptr(ptr), self.length as u64) as usize;
+        unsafe { core::slice::from_raw_parts(ptr, len.max(size_of::<SdtHeader>())) }
+    }
+
+    /// Returns table contents following the header.
+    pub fn data(&'static self) -> &'static [u8] {
+        &self.bytes()[size_of::<SdtHeader>()..]
+    }
+
+    /// Returns whether all bytes of the table sum to zero.
+    pub fn is_valid(&'static self) -> bool {
+        checksum_valid(self.bytes())
+    }
+}
+
+
+/// Returns printable form of ACPI identification string.
+pub fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim_end_matches(['\0', ' '])
+}
+
+
+/// Returns whether given bytes sum to zero, as required for all ACPI structures.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+
+/// Reads value of type `T` at given byte offset of ACPI table data, which are not aligned.
+fn read<T: Copy>(data: &[u8], offset: usize) -> T {
+    assert!(offset + size_of::<T>() <= data.len(), "ACPI table read out of bounds");
+    unsafe { core::ptr::read_unaligned(data.as_ptr().add(offset).cast::<T>()) }
+}
+
+
+/// Reads value of type `T` at given byte offset, if the table is long enough to contain it
+/// (fields were added to some tables in later ACPI revisions).
+fn read_opt<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    (offset + size_of::<T>() <= data.len()).then(|| read(data, offset))
+}
+
+
+/// Returns table header located at given physical address.
+fn table_at(addr: u64) -> &'static SdtHeader {
+    unsafe { &*paging::phys_to_virt(addr).as_ptr::<SdtHeader>() }
+}
+
+
+/// Root System Description Pointer, passed to the kernel by bootloader.
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Physical address of the root table (RSDT or XSDT).
+    pub root_addr: u64,
+    /// Whether the root table is XSDT (with 64-bit entries).
+    pub xsdt: bool,
+}
+
+
+fn old_rsdp_valid(rsdp: &AcpiOldRspd) -> bool {
+    let bytes = unsafe {
+        core::slice::from_raw_parts((rsdp as *const AcpiOldRspd).cast::<u8>(), size_of::<AcpiOldRspd>())
+    };
+    rsdp.has_valid_signature() && checksum_valid(bytes)
+}
+
+
+/// Checks ACPI 2.0 RSDP, whose raw bytes (as long as its multiboot tag) are `data`. Extended
+/// checksum covers `length` bytes, which must fit in the tag.
+fn new_rsdp_valid(rsdp: &AcpiNewRspd, data: &[u8]) -> bool {
+    let len = rsdp.length() as usize;
+    if len < AcpiNewRspd::SIZE || len > data.len() {
+        return false;
+    }
+    old_rsdp_valid(rsdp.old()) && checksum_valid(&data[..len])
+}
+
+
+/// Returns RSDP passed by bootloader, if it has valid checksums. ACPI 2.0 RSDP (pointing to
+/// XSDT) is preferred over the ACPI 1.0 one.
+pub fn rsdp() -> Option<Rsdp> {
+    let mut rsdp = None;
+    for tag in multiboot::boot_info()? {
+        match tag {
+            Tag::AcpiNewRspd { rsdp: new, data } if new.xsdt_addr() != 0 && new_rsdp_valid(new, data) => {
+                return Some(Rsdp {
+                    revision: new.old().revision(),
+                    oem_id: new.old().oem_id(),
+                    root_addr: new.xsdt_addr(),
+                    xsdt: true,
+                });
+            }
+            Tag::AcpiOldRspd(old) if old_rsdp_valid(old) => {
+                rsdp = Some(Rsdp {
+                    revision: old.revision(),
+                    oem_id: old.oem_id(),
+                    root_addr: old.rsdt_addr() as u64,
+                    xsdt: false,
+                });
+            }
+            _ => {}
+        }
+    }
+    rsdp
+}
+
+
+/// Returns root table (RSDT or XSDT).
+pub fn root_table() -> Option<&'static SdtHeader> {
+    Some(table_at(rsdp()?.root_addr))
+}
+
+
+/// Iterator over tables referenced by the root table.
+pub struct Tables {
+    entries: &'static [u8],
+    entry_size: usize,
+}
+
+impl Iterator for Tables {
+    type Item = &'static SdtHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entries.len() < self.entry_size {
+            return None;
+        }
+        let addr = match self.entry_size {
+            4 => read::<u32>(self.entries, 0) as u64,
+            _ => read::<u64>(self.entries, 0),
+        };
+        self.entries = &self.entries[self.entry_size..];
+        Some(table_at(addr))
+    }
+}
+
+
+/// Returns all tables referenced by the root table, including ones with invalid checksum.
+pub fn tables() -> Tables {
+    match rsdp() {
+        Some(rsdp) => Tables {
+            entries: table_at(rsdp.root_addr).data(),
+            entry_size: if rsdp.xsdt { 8 } else { 4 },
+        },
+        None => Tables { entries: &[], entry_size: 4 },
+    }
+}
+
+
+/// Finds ACPI table with given signature and valid checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature && table.is_valid())
+}
+
+
+/// Generic Address Structure, describing register location in one of address spaces.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// Address space of a register described by `GenericAddress`.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    fn parse(data: &[u8], offset: usize) -> Option<GenericAddress> {
+        let bytes = data.get(offset..offset + 12)?;
+        Some(GenericAddress {
+            address_space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read(bytes, 4),
+        })
+    }
+
+    /// Returns whether the structure describes a register (zero address means not present).
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+}
+
+
+/// Fixed ACPI Description Table, describing fixed hardware features (power management
+/// registers, reset register, ...).
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Physical address of the Differentiated System Description Table.
+    pub dsdt: u64,
+    /// Interrupt used by System Control Interrupt.
+    pub sci_interrupt: u16,
+    /// I/O port of System Management Mode command register.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// I/O ports of PM1 event and control blocks.
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm1_control_length: u8,
+    /// I/O port of ACPI power management timer.
+    pub pm_timer_block: u32,
+    /// RTC register holding the century, zero if not supported.
+    pub century: u8,
+    pub boot_arch_flags: u16,
+    pub flags: u32,
+    /// Reset register, if supported (ACPI 2.0+).
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    /// Extended (64-bit) PM1 control blocks (ACPI 2.0+).
+    pub x_pm1a_control_block: Option<GenericAddress>,
+    pub x_pm1b_control_block: Option<GenericAddress>,
+}
+
+#[allow(unused)]
+impl Fadt {
+    /// Flag indicating support of the reset register.
+    pub const RESET_REG_SUP: u32 = 1 << 10;
+    /// Boot architecture flag indicating presence of 8042 keyboard controller.
+    pub const BOOT_ARCH_8042: u16 = 1 << 1;
+}
+
+
+/// Finds and parses the FADT.
+pub fn fadt() -> Option<Fadt> {
+    let data = find_table(b"FACP")?.data();
+    // 64-bit DSDT address takes precedence, if present
+    let dsdt = match read_opt::<u64>(data, 104) {
+        Some(addr) if addr != 0 => addr,
+        _ => read_opt::<u32>(data, 4)? as u64,
+    };
+    // fields of ACPI 1.0 FADT are required, later ones may be missing
+    Some(Fadt {
+        dsdt,
+        sci_interrupt: read_opt(data, 10)?,
+        smi_command: read_opt(data, 12)?,
+        acpi_enable: read_opt(data, 16)?,
+        acpi_disable: read_opt(data, 17)?,
+        pm1a_event_block: read_opt(data, 20)?,
+        pm1b_event_block: read_opt(data, 24)?,
+        pm1a_control_block: read_opt(data, 28)?,
+        pm1b_control_block: read_opt(data, 32)?,
+        pm_timer_block: read_opt(data, 40)?,
+        pm1_control_length: read_opt(data, 53)?,
+        century: read_opt(data, 72).unwrap_or(0),
+        boot_arch_flags: read_opt(data, 73).unwrap_or(0),
+        flags: read_opt(data, 76).unwrap_or(0),
+        reset_register: GenericAddress::parse(data, 80).filter(|r| r.is_present()),
+        reset_value: read_opt(data, 92).unwrap_or(0),
+        x_pm1a_control_block: GenericAddress::parse(data, 136).filter(|r| r.is_present()),
+        x_pm1b_control_block: GenericAddress::parse(data, 148).filter(|r| r.is_present()),
+    })
+}
+
+
+/// Returns the DSDT, referenced by the FADT.
+pub fn dsdt() -> Option<&'static SdtHeader> {
+    let table = table_at(fadt()?.dsdt);
+    (&table.signature == b"DSDT" && table.is_valid()).then_some(table)
+}
+
+
+/// Multiple APIC Description Table, describing interrupt controllers of the system.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    /// Physical address of local APIC of each processor.
+    pub local_apic_addr: u64,
+    /// Bit 0 set indicates the system also has dual 8259 PICs.
+    pub flags: u32,
+    entries: &'static [u8],
+}
+
+/// Entry of the MADT.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    IoApic { id: u8, addr: u32, gsi_base: u32 },
+    /// Describes how an ISA IRQ is connected to I/O APIC inputs.
+    InterruptSourceOverride { bus: u8, source: u8, gsi: u32, flags: u16 },
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },
+    LocalApicAddressOverride { addr: u64 },
+    Unknown { typ: u8 },
+}
+
+impl Madt {
+    /// Returns iterator over all MADT entries.
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries { data: self.entries }
+    }
+
+    /// Returns physical address of local APIC, taking address override entry into account.
+    pub fn local_apic_addr(&self) -> u64 {
+        self.entries()
+            .find_map(|e| if let MadtEntry::LocalApicAddressOverride { addr } = e { Some(addr) } else { None })
+            .unwrap_or(self.local_apic_addr)
+    }
+}
+
+/// Iterator over MADT entries.
+pub struct MadtEntries {
+    data: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let (typ, len) = (self.data[0], self.data[1] as usize);
+        if len < 2 || len > self.data.len() {
+            return None;
+        }
+        let e = &self.data[..len];
+        self.data = &self.data[len..];
+        // entries too short for their type are reported as unknown
+        let parse = || match typ {
+            0 if len >= 8 => Some(MadtEntry::LocalApic { processor_id: e[2], apic_id: e[3], flags: read_opt(e, 4)? }),
+            1 if len >= 12 => Some(MadtEntry::IoApic { id: e[2], addr: read_opt(e, 4)?, gsi_base: read_opt(e, 8)? }),
+            2 if len >= 10 => Some(MadtEntry::InterruptSourceOverride {
+                bus: e[2], source: e[3], gsi: read_opt(e, 4)?, flags: read_opt(e, 8)?,
+            }),
+            4 if len >= 6 => Some(MadtEntry::LocalApicNmi { processor_id: e[2], flags: read_opt(e, 3)?, lint: e[5] }),
+            5 if len >= 12 => Some(MadtEntry::LocalApicAddressOverride { addr: read_opt(e, 4)? }),
+            _ => None,
+        };
+        Some(parse().unwrap_or(MadtEntry::Unknown { typ }))
+    }
+}
+
+
+/// Finds and parses the MADT.
+pub fn madt() -> Option<Madt> {
+    let data = find_table(b"APIC")?.data();
+    Some(Madt {
+        local_apic_addr: read_opt::<u32>(data, 0)? as u64,
+        flags: read_opt(data, 4)?,
+        entries: data.get(8..)?,
+    })
+}
+
+
+/// High Precision Event Timer description table.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// Hardware revision, number of comparators and vendor of the timer block.
+    pub event_timer_block_id: u32,
+    /// Location of HPET registers.
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// Minimal tick count in periodic mode without lost interrupts.
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+
+/// Finds and parses the HPET table.
+pub fn hpet() -> Option<Hpet> {
+    let data = find_table(b"HPET")?.data();
+    Some(Hpet {
+        event_timer_block_id: read_opt(data, 0)?,
+        base_address: GenericAddress::parse(data, 4)?,
+        hpet_number: read_opt(data, 16).unwrap_or(0),
+        minimum_tick: read_opt(data, 17).unwrap_or(0),
+        page_protection: read_opt(data, 19).unwrap_or(0),
+    })
+}
+
+
+/// PCI Express memory mapped configuration space description table.
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    entries: &'static [u8],
+}
+
+/// Configuration space of single PCI segment group.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space.
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    const ENTRY_SIZE: usize = 16;
+
+    /// Returns iterator over configuration spaces.
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + use<> {
+        self.entries.chunks_exact(Self::ENTRY_SIZE).map(|e| McfgEntry {
+            base_address: read(e, 0),
+            segment_group: read(e, 8),
+            start_bus: e[10],
+            end_bus: e[11],
+        })
+    }
+}
+
+
+/// Finds and parses the MCFG table.
+pub fn mcfg() -> Option<Mcfg> {
+    let data = find_table(b"MCFG")?.data();
+    // entries follow 8 reserved bytes
+    Some(Mcfg { entries: data.get(8..)? })
+}
@@ -1,88 +1,565 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-
-use crate::multiboot;
 use x86_64::structures::paging as Paging;
 
+use crate::{cmdline, frame, multiboot, paging, vga_printf};
+
 
-pub const KERNEL_HEAP_START: u64 = 0x700000000000;
-pub const KERNEL_HEAP_END: u64 = KERNEL_HEAP_START + 1024 * 1024 - 1;
-pub const KERNEL_HEAP_SIZE: u64 = KERNEL_HEAP_END - KERNEL_HEAP_START;
+pub const KERNEL_HEAP_START: u64 = 0xFFFF_C000_0000_0000;
+/// Size of virtual address space reserved for the heap - hard upper bound of the heap limit.
+pub const KERNEL_HEAP_REGION_SIZE: u64 = 256 * 1024 * 1024;
+/// Default maximum amount of memory the heap can have mapped.
+pub const KERNEL_HEAP_DEFAULT_LIMIT: u64 = 64 * 1024 * 1024;
+/// Object sizes served by slabs. Larger allocations are served by the page allocator.
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+const PAGE_SIZE: usize = 4096;
+const HEAP_PAGES: usize = KERNEL_HEAP_REGION_SIZE as usize / PAGE_SIZE;
 
 #[global_allocator]
-static GLOBAL_ALLOC: linked_list_allocator::LockedHeap = linked_list_allocator::LockedHeap::empty();
+static GLOBAL_ALLOC: KernelHeap = KernelHeap::new();
+
+
+/// Kernel heap allocator. Small objects are carved out of per size class slabs, larger ones get
+/// whole pages mapped on demand in the heap region.
+pub struct KernelHeap {
+    inner: spin::Mutex<SlabHeap>,
+    /// Number of allocations which could not be satisfied.
+    failed_allocs: AtomicUsize,
+    /// Size and alignment of the last allocation which could not be satisfied.
+    failed_size: AtomicUsize,
+    failed_align: AtomicUsize,
+}
+
+
+struct SlabHeap {
+    classes: [SizeClass; SIZE_CLASSES.len()],
+    pages: HeapPages,
+    /// Number of large (page allocator) allocations currently in use.
+    large_allocs: usize,
+    /// Number of pages used by large allocations.
+    large_pages: usize,
+    /// Total number of large allocations served since boot.
+    large_total: usize,
+}
+
+
+/// Free object inside slab page, linking to the next free object of the same size class.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+
+/// Slab cache for objects of a single size.
+struct SizeClass {
+    size: usize,
+    free_list: *mut FreeObject,
+    /// Number of pages owned by this class.
+    pages: usize,
+    /// Number of objects currently allocated.
+    in_use: usize,
+    /// Total number of allocations served since boot.
+    total_allocs: usize,
+}
+
+
+/// Page granular allocator of the heap virtual region, mapping frames on demand.
+struct HeapPages {
+    /// One bit per heap page - set bit means the page is mapped and in use.
+    used: [u64; HEAP_PAGES / 64],
+    /// Number of currently mapped pages.
+    mapped: usize,
+    /// Maximum number of mapped pages.
+    limit: usize,
+}
+
+
+/// Usage counters of a single slab size class.
+#[derive(Clone, Copy, Debug)]
+pub struct SizeClassStats {
+    /// Object size in bytes.
+    pub size: usize,
+    /// Number of pages owned by the class.
+    pub pages: usize,
+    /// Objects currently allocated.
+    pub in_use: usize,
+    /// Objects fitting into pages owned by the class.
+    pub capacity: usize,
+    /// Total number of allocations served since boot.
+    pub total_allocs: usize,
+}
+
+
+/// Snapshot of heap usage.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Currently mapped heap size in bytes.
+    pub size: usize,
+    /// Bytes used by allocations (rounded to size class or page).
+    pub used: usize,
+    /// Bytes mapped, but not used by any allocation.
+    pub free: usize,
+    /// Maximum heap size in bytes.
+    pub limit: usize,
+    /// Number of allocations which failed.
+    pub failed_allocs: usize,
+    /// Number of large allocations currently in use.
+    pub large_allocs: usize,
+    /// Number of pages used by large allocations.
+    pub large_pages: usize,
+    /// Largest block (in bytes), which can still be allocated.
+    pub largest_free_block: usize,
+    /// Number of allocations currently in use.
+    pub allocations: usize,
+    /// Total number of allocations served since boot.
+    pub total_allocs: usize,
+}
+
+
+/// Snapshot of physical memory and heap usage.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryStats {
+    /// Size of all memory regions reported by the bootloader.
+    pub total_physical: u64,
+    /// Size of memory regions reported as available RAM.
+    pub usable_physical: u64,
+    /// Frames managed by the frame allocator (available RAM minus reserved regions).
+    pub usable_frames: usize,
+    /// Frames currently handed out by the frame allocator.
+    pub allocated_frames: usize,
+    /// Frames currently free.
+    pub free_frames: usize,
+    pub heap: HeapStats,
+}
+
+
+// heap is only ever accessed through the mutex
+unsafe impl Send for SlabHeap {}
+
+
+impl SizeClass {
+    const fn new(size: usize) -> Self {
+        Self {
+            size,
+            free_list: core::ptr::null_mut(),
+            pages: 0,
+            in_use: 0,
+            total_allocs: 0,
+        }
+    }
+
+    /// Splits fresh page into objects and pushes them to the free list.
+    fn add_page(&mut self, page: u64) {
+        for i in (0..PAGE_SIZE / self.size).rev() {
+            let obj = (page as usize + i * self.size) as *mut FreeObject;
+            unsafe { obj.write(FreeObject { next: self.free_list }) };
+            self.free_list = obj;
+        }
+        self.pages += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.free_list.is_null() {
+            return None;
+        }
+        let obj = self.free_list;
+        self.free_list = unsafe { (*obj).next };
+        self.in_use += 1;
+        self.total_allocs += 1;
+        Some(obj as *mut u8)
+    }
+
+    fn push(&mut self, ptr: *mut u8) {
+        let obj = ptr as *mut FreeObject;
+        unsafe { obj.write(FreeObject { next: self.free_list }) };
+        self.free_list = obj;
+        self.in_use -= 1;
+    }
+
+    fn stats(&self) -> SizeClassStats {
+        SizeClassStats {
+            size: self.size,
+            pages: self.pages,
+            in_use: self.in_use,
+            capacity: self.pages * (PAGE_SIZE / self.size),
+            total_allocs: self.total_allocs,
+        }
+    }
+}
+
+
+impl HeapPages {
+    const fn new() -> Self {
+        Self {
+            used: [0; HEAP_PAGES / 64],
+            mapped: 0,
+            limit: KERNEL_HEAP_DEFAULT_LIMIT as usize / PAGE_SIZE,
+        }
+    }
+
+    fn is_used(&self, idx: usize) -> bool {
+        self.used[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn set_used(&mut self, idx: usize, used: bool) {
+        if used {
+            self.used[idx / 64] |= 1 << (idx % 64);
+        } else {
+            self.used[idx / 64] &= !(1 << (idx % 64));
+        }
+    }
+
+    /// Finds `count` contiguous free pages, first one aligned to `align` pages.
+    fn find_free(&self, count: usize, align: usize) -> Option<usize> {
+        let mut start = 0;
+        while start + count <= HEAP_PAGES {
+            match (start..start + count).find(|&idx| self.is_used(idx)) {
+                Some(idx) => start = (idx + 1).next_multiple_of(align),
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// Returns length (in pages) of the longest run of free pages, which could still be mapped.
+    fn largest_free_run(&self) -> usize {
+        let (mut best, mut run) = (0, 0);
+        for idx in 0..HEAP_PAGES {
+            if self.is_used(idx) {
+                run = 0;
+            } else {
+                run += 1;
+                best = best.max(run);
+            }
+        }
+        best.min(self.limit.saturating_sub(self.mapped))
+    }
+
+    /// Maps `count` contiguous pages using given mapper and frame allocator. Returns virtual
+    /// address of the first page.
+    fn allocate(
+        &mut self,
+        count: usize,
+        align: usize,
+        mapper: &mut impl Paging::Mapper<Paging::Size4KiB>,
+        frame_alloc: &mut impl Paging::FrameAllocator<Paging::Size4KiB>,
+    ) -> Result<u64, Paging::mapper::MapToError<Paging::Size4KiB>> {
+        if self.mapped + count > self.limit {
+            return Err(Paging::mapper::MapToError::FrameAllocationFailed);
+        }
+        let start = self.find_free(count, align)
+            .ok_or(Paging::mapper::MapToError::FrameAllocationFailed)?;
+        let addr = KERNEL_HEAP_START + (start * PAGE_SIZE) as u64;
+        // flags to use for new mapped pages
+        let flags = Paging::PageTableFlags::PRESENT
+            | Paging::PageTableFlags::WRITABLE
+            | paging::no_execute_flag();
 
+        for idx in start..(start + count) {
+            let page = Paging::Page::containing_address(
+                x86_64::VirtAddr::new(KERNEL_HEAP_START + (idx * PAGE_SIZE) as u64)
+            );
+            let result = match frame_alloc.allocate_frame() {
+                Some(frame) => unsafe { mapper.map_to(page, frame, flags, frame_alloc) }
+                    .inspect_err(|_| {
+                        // frame was not mapped, give it back
+                        unsafe { frame::deallocate_frames(frame, 1) };
+                    }),
+                None => Err(Paging::mapper::MapToError::FrameAllocationFailed),
+            };
+            match result {
+                Ok(f) => f.flush(),
+                Err(e) => {
+                    // roll back pages mapped so far
+                    self.release(addr, idx - start, mapper);
+                    return Err(e);
+                }
+            }
+            self.set_used(idx, true);
+            self.mapped += 1;
+        }
+        Ok(addr)
+    }
 
-pub struct NormalFrameAllocator<'a> {
-    mem_map: &'a [multiboot::MemoryMapEntry],
-    next: u64,
+    /// Unmaps `count` pages starting at `addr` using given mapper and returns their frames.
+    fn release(&mut self, addr: u64, count: usize, mapper: &mut impl Paging::Mapper<Paging::Size4KiB>) {
+        let first = ((addr - KERNEL_HEAP_START) as usize) / PAGE_SIZE;
+        for idx in first..(first + count) {
+            let page: Paging::Page = Paging::Page::containing_address(
+                x86_64::VirtAddr::new(KERNEL_HEAP_START + (idx * PAGE_SIZE) as u64)
+            );
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    unsafe { frame::deallocate_frames(frame, 1) };
+                },
+                Err(e) => panic!("failed to unmap heap page {:?}: {:?}", page, e),
+            }
+            self.set_used(idx, false);
+            self.mapped -= 1;
+        }
+    }
 }
 
 
-#[allow(dead_code)]
-impl<'a> NormalFrameAllocator<'a> {
-    pub fn new(mem_map: &'a [multiboot::MemoryMapEntry]) -> Self {
+impl SlabHeap {
+    const fn new() -> Self {
         Self {
-            mem_map,
-            next: 0,
+            classes: [
+                SizeClass::new(SIZE_CLASSES[0]),
+                SizeClass::new(SIZE_CLASSES[1]),
+                SizeClass::new(SIZE_CLASSES[2]),
+                SizeClass::new(SIZE_CLASSES[3]),
+                SizeClass::new(SIZE_CLASSES[4]),
+                SizeClass::new(SIZE_CLASSES[5]),
+                SizeClass::new(SIZE_CLASSES[6]),
+                SizeClass::new(SIZE_CLASSES[7]),
+                SizeClass::new(SIZE_CLASSES[8]),
+            ],
+            pages: HeapPages::new(),
+            large_allocs: 0,
+            large_pages: 0,
+            large_total: 0,
+        }
+    }
+
+    /// Returns index of size class serving given layout, if the layout is small enough.
+    fn class_index(layout: &Layout) -> Option<usize> {
+        // objects are aligned to their size, so alignment only bumps the size class
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&s| s >= size)
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+        match Self::class_index(&layout) {
+            Some(idx) => {
+                if let Some(ptr) = self.classes[idx].pop() {
+                    return Some(ptr);
+                }
+                // slab is empty, get a new page for it
+                let mut mapper = paging::get_page_mapper(None);
+                let page = self.pages.allocate(1, 1, &mut mapper, &mut frame::KernelFrameAllocator).ok()?;
+                self.classes[idx].add_page(page);
+                self.classes[idx].pop()
+            },
+            None => {
+                let count = layout.size().div_ceil(PAGE_SIZE);
+                let align = layout.align().div_ceil(PAGE_SIZE).max(1);
+                let mut mapper = paging::get_page_mapper(None);
+                let addr = self.pages.allocate(count, align, &mut mapper, &mut frame::KernelFrameAllocator).ok()?;
+                self.large_allocs += 1;
+                self.large_pages += count;
+                self.large_total += 1;
+                Some(addr as *mut u8)
+            }
         }
     }
 
-    pub fn frame_iter(&self) -> impl Iterator<Item = Paging::PhysFrame> {
-        self
-            .mem_map
-            .iter()
-            .filter(|m| m.typ == multiboot::MemoryMapType::Available)
-            .map(|m| m.base_addr..(m.base_addr+m.length))
-            .flat_map(|m| m.step_by(4096))
-            .map(|m| Paging::PhysFrame::containing_address(x86_64::PhysAddr::new(m)))
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match Self::class_index(&layout) {
+            Some(idx) => self.classes[idx].push(ptr),
+            None => {
+                let count = layout.size().div_ceil(PAGE_SIZE);
+                let mut mapper = paging::get_page_mapper(None);
+                self.pages.release(ptr as u64, count, &mut mapper);
+                self.large_allocs -= 1;
+                self.large_pages -= count;
+            }
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        let size = self.pages.mapped * PAGE_SIZE;
+        let used = self.classes.iter().map(|c| c.in_use * c.size).sum::<usize>()
+            + self.large_pages * PAGE_SIZE;
+        // either fresh pages, or at least a free object in one of the slabs
+        let largest_free_block = (self.pages.largest_free_run() * PAGE_SIZE).max(
+            self.classes.iter().filter(|c| !c.free_list.is_null()).map(|c| c.size).max().unwrap_or(0)
+        );
+        HeapStats {
+            size,
+            used,
+            free: size - used,
+            limit: self.pages.limit * PAGE_SIZE,
+            failed_allocs: 0,
+            large_allocs: self.large_allocs,
+            large_pages: self.large_pages,
+            largest_free_block,
+            allocations: self.classes.iter().map(|c| c.in_use).sum::<usize>() + self.large_allocs,
+            total_allocs: self.classes.iter().map(|c| c.total_allocs).sum::<usize>() + self.large_total,
+        }
     }
 }
 
 
-unsafe impl<'a> Paging::FrameAllocator<Paging::Size4KiB> for NormalFrameAllocator<'a> {
-    fn allocate_frame(&mut self) -> Option<Paging::PhysFrame> {
-        let frame = self
-            .frame_iter()
-            .nth(self.next as usize);
-        self.next += 1;
-        frame
+impl KernelHeap {
+    const fn new() -> Self {
+        Self {
+            inner: spin::Mutex::new(SlabHeap::new()),
+            failed_allocs: AtomicUsize::new(0),
+            failed_size: AtomicUsize::new(0),
+            failed_align: AtomicUsize::new(0),
+        }
     }
 }
 
 
-/// Initialise heap address space by allocating and mapping required pages and loading initial
-/// linked list heap allocator.
-/// Heap is allocated on address space from 0x7000 0000 0000, with 1 MiB size.
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // heap may be used from interrupt handlers as well
+        let result = x86_64::instructions::interrupts::without_interrupts(|| {
+            self.inner.lock().alloc(layout)
+        });
+        match result {
+            Some(ptr) => ptr,
+            None => {
+                // nothing is printed here, the allocator may be called with the VGA writer locked
+                self.failed_size.store(layout.size(), Ordering::Relaxed);
+                self.failed_align.store(layout.align(), Ordering::Relaxed);
+                self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+                core::ptr::null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.inner.lock().dealloc(ptr, layout);
+        });
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // growing vectors often stay within the same size class, no need to move them then
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let old_class = SlabHeap::class_index(&layout);
+        if old_class.is_some() && old_class == SlabHeap::class_index(&new_layout) {
+            return ptr;
+        }
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+
+/// Reports the last failed allocation together with heap statistics, if any allocation failed.
+/// Called by the panic handler, which failed allocations end in. The heap is not waited for, in
+/// case the panic happened while it was locked.
+pub fn report_out_of_memory() {
+    let failed_allocs = GLOBAL_ALLOC.failed_allocs.load(Ordering::Relaxed);
+    if failed_allocs == 0 {
+        return;
+    }
+    vga_printf!(
+        "\n[heap] OUT OF MEMORY: failed to allocate {} B (align {})\n",
+        GLOBAL_ALLOC.failed_size.load(Ordering::Relaxed), GLOBAL_ALLOC.failed_align.load(Ordering::Relaxed)
+    );
+    if let Some(heap) = GLOBAL_ALLOC.inner.try_lock() {
+        let stats = heap.stats();
+        vga_printf!(
+            "[heap] size {} KiB / limit {} KiB, used {} B, free {} B, {} failed allocations\n",
+            stats.size / 1024, stats.limit / 1024, stats.used, stats.free, failed_allocs
+        );
+    }
+}
+
+
+/// Parses heap size given in bytes, or with `K`, `M` or `G` suffix.
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, unit) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 1024),
+        b'M' | b'm' => (&value[..value.len() - 1], 1024 * 1024),
+        b'G' | b'g' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+
+/// Initialise heap address space by mapping first slab page of every size class using given
+/// mapper and frame allocator.
+/// Heap lives in address space from 0xFFFF C000 0000 0000 and grows on demand up to the heap
+/// limit, which can be changed by `heap_limit=` command line option (e.g. `heap_limit=128M`).
 pub fn heap_init(
     mapper: &mut impl Paging::Mapper<Paging::Size4KiB>,
     frame_alloc: &mut impl Paging::FrameAllocator<Paging::Size4KiB>
 ) -> Result<(), Paging::mapper::MapToError<Paging::Size4KiB>> {
-    // create page range from specified address range
-    let range = Paging::Page::range_inclusive(
-        Paging::Page::containing_address(x86_64::VirtAddr::new(KERNEL_HEAP_START)),
-        Paging::Page::containing_address(x86_64::VirtAddr::new(KERNEL_HEAP_END))
-    );
+    if let Some(value) = cmdline::option("heap_limit") {
+        match parse_size(value) {
+            Some(bytes) if bytes >= (SIZE_CLASSES.len() * PAGE_SIZE) as u64 => set_heap_limit(bytes),
+            _ => vga_printf!(
+                "[boot] invalid heap_limit option, using {} MiB\n", KERNEL_HEAP_DEFAULT_LIMIT / 1024 / 1024
+            ),
+        }
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut heap = GLOBAL_ALLOC.inner.lock();
+        for idx in 0..SIZE_CLASSES.len() {
+            let page = heap.pages.allocate(1, 1, mapper, frame_alloc)?;
+            heap.classes[idx].add_page(page);
+        }
+        Ok(())
+    })
+}
 
-    // flags to use for new mapped pages
-    let flags = Paging::PageTableFlags::PRESENT | Paging::PageTableFlags::WRITABLE;
-
-    // map each page in range
-    for page in range {
-        // allocate new frame
-        let frame = match frame_alloc.allocate_frame() {
-            Some(f) => f,
-            None => return Err(Paging::mapper::MapToError::FrameAllocationFailed),
-        };
-        unsafe {
-            // map the new frame
-            match mapper.map_to(page, frame, flags, frame_alloc) {
-                Ok(f) => f.flush(),
-                Err(e) => return Err(e),
+
+/// Sets maximum size the heap may grow to. Limit lower than current heap size only prevents
+/// further growth.
+pub fn set_heap_limit(bytes: u64) {
+    let pages = bytes.min(KERNEL_HEAP_REGION_SIZE) as usize / PAGE_SIZE;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        GLOBAL_ALLOC.inner.lock().pages.limit = pages;
+    });
+}
+
+
+/// Returns current heap usage statistics.
+pub fn heap_stats() -> HeapStats {
+    let mut stats = x86_64::instructions::interrupts::without_interrupts(|| GLOBAL_ALLOC.inner.lock().stats());
+    stats.failed_allocs = GLOBAL_ALLOC.failed_allocs.load(Ordering::Relaxed);
+    stats
+}
+
+
+/// Returns usage counters of every slab size class.
+pub fn size_class_stats() -> [SizeClassStats; SIZE_CLASSES.len()] {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let heap = GLOBAL_ALLOC.inner.lock();
+        core::array::from_fn(|i| heap.classes[i].stats())
+    })
+}
+
+
+/// Returns physical memory statistics (from bootloader memory map and frame allocator) together
+/// with heap statistics.
+pub fn memory_stats() -> MemoryStats {
+    let (mut total_physical, mut usable_physical) = (0, 0);
+    if let Some(mbi) = multiboot::boot_info() {
+        let mem_map = mbi
+            .filter_map(|x| if let multiboot::Tag::MemoryMap(m) = x { Some(m) } else { None })
+            .next()
+            .unwrap_or(&[]);
+        for entry in mem_map {
+            total_physical += entry.length;
+            if entry.typ == multiboot::MemoryMapType::Available {
+                usable_physical += entry.length;
             }
         }
     }
-    // loading the linked list allocator
-    unsafe {GLOBAL_ALLOC.lock().init(KERNEL_HEAP_START as *mut u8, KERNEL_HEAP_SIZE as usize)};
-
-    Ok(())
+    let usable_frames = frame::usable_frames();
+    let free_frames = frame::free_frames();
+    MemoryStats {
+        total_physical,
+        usable_physical,
+        usable_frames,
+        allocated_frames: usable_frames - free_frames,
+        free_frames,
+        heap: heap_stats(),
+    }
 }
@@ -0,0 +1,287 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::acpi::{self, MadtEntry};
+use crate::paging;
+use crate::pic::{self, IRQ, IRQ_LINES};
+use crate::pit;
+
+/// Vector of local APIC spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Maximum number of I/O APICs the kernel keeps track of.
+const MAX_IO_APICS: usize = 8;
+
+// local APIC registers (offsets from its base address)
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_SVR: u64 = 0xF0;
+const LAPIC_ISR: u64 = 0x100;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_LVT_LINT0: u64 = 0x350;
+const LAPIC_LVT_LINT1: u64 = 0x360;
+const LAPIC_LVT_ERROR: u64 = 0x370;
+const LAPIC_TIMER_INITIAL: u64 = 0x380;
+const LAPIC_TIMER_CURRENT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3E0;
+
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide configuration value for dividing bus clock by 16.
+const LAPIC_TIMER_DIVIDE_16: u32 = 0x3;
+
+/// Model specific register holding local APIC base address and global enable bit.
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const IA32_APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// I/O APIC registers
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// Length of timer calibration (in ms).
+const CALIBRATION_MS: u32 = 10;
+
+/// Virtual address of local APIC registers, zero while APIC is not used.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Local APIC timer ticks per second (with divider of 16).
+static TIMER_TICKS_PER_SECOND: AtomicU32 = AtomicU32::new(0);
+
+static IO_APICS: spin::Mutex<IoApics> = spin::Mutex::new(IoApics {
+    apics: [None; MAX_IO_APICS],
+    routes: [None; IRQ_LINES],
+});
+
+
+/// Single I/O APIC.
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    /// Virtual address of its registers.
+    base: u64,
+    /// First global system interrupt handled by this I/O APIC.
+    gsi_base: u32,
+    /// Number of its redirection entries.
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            core::ptr::read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            core::ptr::write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+}
+
+
+/// Routing of a legacy IRQ to an I/O APIC input.
+#[derive(Debug, Clone, Copy)]
+struct IrqRoute {
+    apic: usize,
+    /// Redirection entry index within the I/O APIC.
+    entry: u32,
+    /// Low half of the redirection entry (without mask bit).
+    low: u32,
+}
+
+struct IoApics {
+    apics: [Option<IoApic>; MAX_IO_APICS],
+    routes: [Option<IrqRoute>; IRQ_LINES],
+}
+
+impl IoApics {
+    fn set_masked(&self, irq: IRQ, masked: bool) {
+        if let Some(route) = self.routes[irq.line() as usize] {
+            let apic = self.apics[route.apic].expect("IRQ routed to unknown I/O APIC");
+            let low = if masked { route.low | REDIRECTION_MASKED } else { route.low };
+            apic.write(IOAPIC_REDIRECTION_TABLE + route.entry * 2, low);
+        }
+    }
+}
+
+
+fn lapic_read(reg: u64) -> u32 {
+    unsafe { core::ptr::read_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *const u32) }
+}
+
+fn lapic_write(reg: u64, value: u32) {
+    unsafe { core::ptr::write_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *mut u32, value) }
+}
+
+
+/// Returns whether interrupts are delivered through APIC instead of the 8259 PIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+
+/// Returns whether the CPU has a local APIC.
+fn has_apic() -> bool {
+    // CPUID function 1, EDX bit 9 (APIC)
+    core::arch::x86_64::__cpuid(1).edx & (1 << 9) != 0
+}
+
+
+/// Switches interrupt delivery from the 8259 PIC to local APIC and I/O APIC described by ACPI
+/// MADT. All legacy IRQs are routed to their usual vectors, but stay masked. Timer IRQ is
+/// delivered by the local APIC timer (see `start_timer`) instead of the PIT. Returns false
+/// (and leaves the PIC in use) if there is no APIC.
+///
+/// Must be called with interrupts disabled, after physical memory is mapped.
+pub fn init() -> bool {
+    if !has_apic() {
+        return false;
+    }
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+
+    // collect I/O APICs
+    let mut io_apics = IO_APICS.lock();
+    let mut count = 0;
+    for entry in madt.entries() {
+        if let MadtEntry::IoApic { addr, gsi_base, .. } = entry {
+            if count == MAX_IO_APICS {
+                break;
+            }
+            let mut apic = IoApic { base: paging::phys_to_virt(addr as u64).as_u64(), gsi_base, entries: 0 };
+            apic.entries = ((apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+            io_apics.apics[count] = Some(apic);
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return false;
+    }
+
+    // legacy PIC is not used anymore
+    pic::disable();
+
+    // enable local APIC
+    unsafe {
+        let mut msr = x86_64::registers::model_specific::Msr::new(IA32_APIC_BASE_MSR);
+        msr.write(msr.read() | IA32_APIC_BASE_ENABLE);
+    }
+    LAPIC_BASE.store(paging::phys_to_virt(madt.local_apic_addr()).as_u64(), Ordering::Relaxed);
+    lapic_write(LAPIC_TPR, 0);
+    lapic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+    lapic_write(LAPIC_LVT_LINT1, LVT_MASKED);
+    lapic_write(LAPIC_LVT_ERROR, LVT_MASKED);
+    lapic_write(LAPIC_SVR, LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    let apic_id = lapic_read(LAPIC_ID) >> 24;
+
+    // route legacy IRQs (except timer, which is handled by local APIC timer) to this CPU
+    for irq in IRQ::ALL.into_iter().filter(|&irq| irq != IRQ::Timer && irq != IRQ::Cascade) {
+        // ISA interrupts are active high and edge triggered, unless overridden
+        let (gsi, flags) = madt.entries()
+            .find_map(|e| match e {
+                MadtEntry::InterruptSourceOverride { bus: 0, source, gsi, flags } if source == irq.line() => {
+                    Some((gsi, flags))
+                }
+                _ => None,
+            })
+            .unwrap_or((irq.line() as u32, 0));
+        let Some(apic) = io_apics.apics.iter().flatten().position(|a| a.handles(gsi)) else {
+            continue;
+        };
+        let mut low = irq.vector() as u32;
+        if flags & 0x3 == 0x3 {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if (flags >> 2) & 0x3 == 0x3 {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        let io_apic = io_apics.apics[apic].unwrap();
+        let entry = gsi - io_apic.gsi_base;
+        io_apic.write(IOAPIC_REDIRECTION_TABLE + entry * 2 + 1, apic_id << 24);
+        io_apic.write(IOAPIC_REDIRECTION_TABLE + entry * 2, low | REDIRECTION_MASKED);
+        io_apics.routes[irq.line() as usize] = Some(IrqRoute { apic, entry, low });
+    }
+    drop(io_apics);
+
+    calibrate_timer();
+    ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+
+/// Measures frequency of local APIC timer against PIT channel 2.
+fn calibrate_timer() {
+    lapic_write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    pit::one_shot((pit::PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16, || {
+        lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    });
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+    TIMER_TICKS_PER_SECOND.store(elapsed * (1000 / CALIBRATION_MS), Ordering::Relaxed);
+}
+
+
+/// Starts local APIC timer in periodic mode with given frequency. Timer interrupts are
+/// delivered as `IRQ::Timer`, masked until unmasked through `unmask`. Returns actual period
+/// between interrupts in ns.
+pub fn start_timer(frequency: u32) -> u64 {
+    let ticks_per_second = TIMER_TICKS_PER_SECOND.load(Ordering::Relaxed).max(1);
+    let ticks = (ticks_per_second / frequency.max(1)).max(1);
+    let masked = lapic_read(LAPIC_LVT_TIMER) & LVT_MASKED;
+    lapic_write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, IRQ::Timer.vector() as u32 | LVT_TIMER_PERIODIC | masked);
+    lapic_write(LAPIC_TIMER_INITIAL, ticks);
+    ticks as u64 * 1_000_000_000 / ticks_per_second as u64
+}
+
+
+/// Returns frequency of local APIC timer input clock (after divider) in Hz.
+#[allow(unused)]
+pub fn timer_ticks_per_second() -> u32 {
+    TIMER_TICKS_PER_SECOND.load(Ordering::Relaxed)
+}
+
+
+/// Enables delivery of given IRQ.
+pub fn unmask(irq: IRQ) {
+    set_masked(irq, false);
+}
+
+fn set_masked(irq: IRQ, masked: bool) {
+    if irq == IRQ::Timer {
+        let lvt = lapic_read(LAPIC_LVT_TIMER);
+        lapic_write(LAPIC_LVT_TIMER, if masked { lvt | LVT_MASKED } else { lvt & !LVT_MASKED });
+    } else {
+        IO_APICS.lock().set_masked(irq, masked);
+    }
+}
+
+
+/// Signals end of interrupt to local APIC.
+pub fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+/// Returns whether given vector is in service at local APIC.
+/// Vectors raised by the disabled 8259 PIC never are.
+pub fn is_in_service(vector: u8) -> bool {
+    // eight 32 bit registers, 16 bytes apart
+    let reg = LAPIC_ISR + (vector as u64 / 32) * 0x10;
+    lapic_read(reg) & (1 << (vector % 32)) != 0
+}
@@ -1,11 +1,13 @@
 
 use alloc::{
-    collections::BTreeMap, sync::Arc
+    collections::{BTreeMap, BinaryHeap}, sync::Arc
 };
 
 use core::{
+    cmp::Reverse,
+    pin::Pin,
     sync::atomic::{
-        AtomicU64, Ordering
+        AtomicBool, AtomicU64, Ordering
     }, 
     task::{
         Context, Poll, Waker
@@ -14,6 +16,8 @@ use core::{
 
 use crossbeam_queue::ArrayQueue;
 
+use crate::timer;
+
 
 /// Basic structure representing task ID.
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
@@ -155,3 +159,231 @@ impl alloc::task::Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+
+/// Asynchronous sequence of values, counterpart of `Iterator` for async tasks.
+pub trait Stream {
+    type Item;
+
+    /// Attempts to get next value of the stream. Returns `Poll::Ready(None)` once the stream
+    /// is finished, `Poll::Pending` if the value is not available yet (waker from given context
+    /// is woken once it is).
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+
+/// Convenience methods of streams.
+pub trait StreamExt: Stream {
+    /// Returns future resolving to next value of the stream.
+    fn next(&mut self) -> Next<'_, Self> where Self: Unpin {
+        Next { stream: self }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+
+/// Future returned by `StreamExt::next`.
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+
+/// Waker of a single task, which can be woken from an interrupt handler. Waking only marks
+/// the waker as woken and wakes the task by reference, so nothing is allocated or freed in
+/// interrupt context - repeated wakes before the task registers again are coalesced.
+pub struct AtomicWaker {
+    waker: spin::Mutex<Option<Waker>>,
+    woken: AtomicBool,
+}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        Self { waker: spin::Mutex::new(None), woken: AtomicBool::new(false) }
+    }
+
+    /// Registers waker of the polling task, replacing the previous one.
+    pub fn register(&self, waker: &Waker) {
+        let old = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            self.woken.store(false, Ordering::Relaxed);
+            match &*slot {
+                Some(current) if current.will_wake(waker) => None,
+                _ => slot.replace(waker.clone()),
+            }
+        });
+        // previous waker is dropped with interrupts enabled
+        drop(old);
+    }
+
+    /// Wakes the registered task, unless it was already woken since its last registration.
+    pub fn wake(&self) {
+        if self.woken.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        // lock is only held with interrupts disabled, so it cannot be taken here
+        if let Some(slot) = self.waker.try_lock()
+            && let Some(waker) = &*slot
+        {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+
+/// Pending timers, ordered by their deadline. Wakers are only woken (never dropped) from the
+/// timer interrupt, so no memory is freed in interrupt context - they are removed by their
+/// owners instead. Always locked with interrupts disabled outside of the timer interrupt.
+struct TimerQueue {
+    /// Deadlines (in ns of uptime) and IDs of registered timers.
+    deadlines: BinaryHeap<Reverse<(u64, u64)>>,
+    wakers: BTreeMap<u64, Waker>,
+}
+
+static TIMERS: spin::Mutex<TimerQueue> = spin::Mutex::new(TimerQueue {
+    deadlines: BinaryHeap::new(),
+    wakers: BTreeMap::new(),
+});
+
+
+/// Wakes all tasks waiting for timers, whose deadline is not later than `now`. Called from
+/// the timer interrupt.
+pub fn wake_expired_timers(now: u64) {
+    let mut timers = TIMERS.lock();
+    while let Some(&Reverse((deadline, id))) = timers.deadlines.peek() {
+        if deadline > now {
+            break;
+        }
+        timers.deadlines.pop();
+        // timer may have been cancelled meanwhile
+        if let Some(waker) = timers.wakers.get(&id) {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+
+/// Registration of a single deadline in the timer queue.
+struct TimerEntry {
+    id: u64,
+    deadline: u64,
+    registered: bool,
+}
+
+impl TimerEntry {
+    fn new(deadline: u64) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self { id: NEXT_ID.fetch_add(1, Ordering::Relaxed), deadline, registered: false }
+    }
+
+    /// Returns whether the deadline has passed, otherwise (re)registers waker of given context.
+    fn poll_expired(&mut self, cx: &mut Context) -> bool {
+        if timer::uptime_ns() >= self.deadline {
+            self.cancel();
+            return true;
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            if !self.registered {
+                timers.deadlines.push(Reverse((self.deadline, self.id)));
+                self.registered = true;
+            }
+            match timers.wakers.get_mut(&self.id) {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                Some(waker) => *waker = cx.waker().clone(),
+                None => { timers.wakers.insert(self.id, cx.waker().clone()); }
+            }
+        });
+        false
+    }
+
+    /// Removes waker of the timer from the queue. Its deadline is discarded once it expires.
+    fn cancel(&mut self) {
+        if self.registered {
+            let waker = x86_64::instructions::interrupts::without_interrupts(|| {
+                TIMERS.lock().wakers.remove(&self.id)
+            });
+            // waker is dropped with interrupts enabled
+            drop(waker);
+            self.registered = false;
+        }
+    }
+}
+
+impl Drop for TimerEntry {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+
+/// Future completing once given time passes.
+pub struct Sleep {
+    entry: TimerEntry,
+}
+
+impl Sleep {
+    /// Creates future completing once uptime reaches given deadline (in ns).
+    pub fn until(deadline: u64) -> Self {
+        Self { entry: TimerEntry::new(deadline) }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.entry.poll_expired(cx) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+
+/// Returns future completing after given number of milliseconds.
+#[allow(unused)]
+pub fn sleep_ms(ms: u64) -> Sleep {
+    Sleep::until(timer::uptime_ns() + ms * 1_000_000)
+}
+
+
+/// Stream yielding periodically, with number of periods elapsed since its creation. Missed
+/// periods (when the task could not run in time) are skipped.
+pub struct Interval {
+    entry: TimerEntry,
+    start: u64,
+    period: u64,
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        if !self.entry.poll_expired(cx) {
+            return Poll::Pending;
+        }
+        let elapsed = (timer::uptime_ns() - self.start) / self.period;
+        let next = self.start + (elapsed + 1) * self.period;
+        self.entry = TimerEntry::new(next);
+        Poll::Ready(Some(elapsed))
+    }
+}
+
+
+/// Returns stream yielding every `ms` milliseconds.
+#[allow(unused)]
+pub fn interval_ms(ms: u64) -> Interval {
+    let start = timer::uptime_ns();
+    let period = ms.max(1) * 1_000_000;
+    Interval { entry: TimerEntry::new(start + period), start, period }
+}
@@ -0,0 +1,210 @@
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use crate::acpi::{self, AddressSpace};
+use crate::{cmdline, paging, pit, timer, vga_printf};
+
+// HPET registers (offsets from its base address)
+const HPET_CAPABILITIES: u64 = 0x00;
+const HPET_CONFIG: u64 = 0x10;
+const HPET_MAIN_COUNTER: u64 = 0xF0;
+/// Capabilities: main counter is 64 bits wide.
+const HPET_COUNT_SIZE_64: u64 = 1 << 13;
+/// Configuration: main counter runs.
+const HPET_ENABLE: u64 = 1 << 0;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// Length of TSC calibration (in ms).
+const CALIBRATION_MS: u64 = 50;
+
+
+/// Source of monotonic time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// Timer interrupt counter (PIT or local APIC timer), resolution of one timer tick.
+    Tick,
+    /// High Precision Event Timer.
+    Hpet,
+    /// Invariant Time Stamp Counter of the CPU.
+    Tsc,
+}
+
+impl ClockSource {
+    /// Returns name of the clock source, as used by `clocksource=` command line option.
+    pub fn name(self) -> &'static str {
+        match self {
+            ClockSource::Tick => "tick",
+            ClockSource::Hpet => "hpet",
+            ClockSource::Tsc => "tsc",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [ClockSource::Tick, ClockSource::Hpet, ClockSource::Tsc]
+            .into_iter()
+            .find(|source| source.name() == name)
+    }
+}
+
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Tick as u8);
+/// Virtual address of HPET registers, zero if there is no usable HPET.
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+/// HPET counter period in femtoseconds.
+static HPET_PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// TSC frequency in Hz, zero if TSC is not usable.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Counter value of selected clock source at the time it was selected.
+static COUNTER_BASE: AtomicU64 = AtomicU64::new(0);
+
+
+fn hpet_read(reg: u64) -> u64 {
+    unsafe { core::ptr::read_volatile((HPET_BASE.load(Ordering::Relaxed) + reg) as *const u64) }
+}
+
+fn hpet_write(reg: u64, value: u64) {
+    unsafe { core::ptr::write_volatile((HPET_BASE.load(Ordering::Relaxed) + reg) as *mut u64, value) }
+}
+
+
+/// Finds HPET using ACPI and starts its main counter. Only HPETs with 64-bit counter are used,
+/// so wraparound does not need to be handled.
+fn init_hpet() -> bool {
+    let Some(hpet) = acpi::hpet() else {
+        return false;
+    };
+    if hpet.base_address.address_space != AddressSpace::SystemMemory {
+        return false;
+    }
+    HPET_BASE.store(paging::phys_to_virt(hpet.base_address.address).as_u64(), Ordering::Relaxed);
+    let capabilities = hpet_read(HPET_CAPABILITIES);
+    let period = capabilities >> 32;
+    if capabilities & HPET_COUNT_SIZE_64 == 0 || period == 0 || period > 100_000_000 {
+        HPET_BASE.store(0, Ordering::Relaxed);
+        return false;
+    }
+    HPET_PERIOD_FS.store(period, Ordering::Relaxed);
+    hpet_write(HPET_CONFIG, hpet_read(HPET_CONFIG) | HPET_ENABLE);
+    true
+}
+
+
+/// Returns whether TSC runs at constant rate regardless of power states.
+fn has_invariant_tsc() -> bool {
+    let max_extended = core::arch::x86_64::__cpuid(0x80000000).eax;
+    // CPUID extended function 0x80000007, EDX bit 8 (invariant TSC)
+    max_extended >= 0x80000007 && core::arch::x86_64::__cpuid(0x80000007).edx & (1 << 8) != 0
+}
+
+
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+
+/// Measures TSC frequency against HPET if available, PIT channel 2 otherwise.
+fn calibrate_tsc() -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if HPET_BASE.load(Ordering::Relaxed) != 0 {
+            let hpet_ticks = CALIBRATION_MS * 1_000_000_000_000 / HPET_PERIOD_FS.load(Ordering::Relaxed);
+            let (hpet_start, tsc_start) = (hpet_read(HPET_MAIN_COUNTER), read_tsc());
+            while hpet_read(HPET_MAIN_COUNTER) - hpet_start < hpet_ticks {
+                core::hint::spin_loop();
+            }
+            let (hpet_elapsed, tsc_elapsed) = (hpet_read(HPET_MAIN_COUNTER) - hpet_start, read_tsc() - tsc_start);
+            let elapsed_fs = hpet_elapsed as u128 * HPET_PERIOD_FS.load(Ordering::Relaxed) as u128;
+            (tsc_elapsed as u128 * FEMTOSECONDS_PER_SECOND as u128 / elapsed_fs) as u64
+        } else {
+            let mut tsc_start = 0;
+            pit::one_shot((pit::PIT_FREQUENCY as u64 * CALIBRATION_MS / 1000) as u16, || tsc_start = read_tsc());
+            (read_tsc() - tsc_start) * 1000 / CALIBRATION_MS
+        }
+    })
+}
+
+
+/// Detects available clock sources and selects the one given by `clocksource=` command line
+/// option, or the most precise one (TSC, then HPET, then timer ticks). Must be called after physical
+/// memory is mapped and before the timer is started.
+pub fn init() {
+    let hpet = init_hpet();
+    if has_invariant_tsc() {
+        TSC_FREQUENCY.store(calibrate_tsc(), Ordering::Relaxed);
+    }
+    let default = if is_available(ClockSource::Tsc) {
+        ClockSource::Tsc
+    } else if hpet {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Tick
+    };
+    let source = match cmdline::option("clocksource") {
+        Some(name) => match ClockSource::from_name(name) {
+            Some(source) if is_available(source) => source,
+            _ => {
+                vga_printf!("[boot] clock source {} not available, using {}\n", name, default.name());
+                default
+            }
+        },
+        None => default,
+    };
+    select(source);
+}
+
+
+/// Returns whether given clock source can be used.
+pub fn is_available(source: ClockSource) -> bool {
+    match source {
+        ClockSource::Tick => true,
+        ClockSource::Hpet => HPET_BASE.load(Ordering::Relaxed) != 0,
+        ClockSource::Tsc => TSC_FREQUENCY.load(Ordering::Relaxed) != 0,
+    }
+}
+
+
+fn select(source: ClockSource) {
+    let counter = match source {
+        ClockSource::Tick => 0,
+        ClockSource::Hpet => hpet_read(HPET_MAIN_COUNTER),
+        ClockSource::Tsc => read_tsc(),
+    };
+    COUNTER_BASE.store(counter, Ordering::Relaxed);
+    SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+
+/// Returns currently used clock source.
+pub fn source() -> ClockSource {
+    match SOURCE.load(Ordering::Relaxed) {
+        x if x == ClockSource::Hpet as u8 => ClockSource::Hpet,
+        x if x == ClockSource::Tsc as u8 => ClockSource::Tsc,
+        _ => ClockSource::Tick,
+    }
+}
+
+
+/// Returns frequency of the current clock source counter (in Hz).
+pub fn frequency() -> u64 {
+    match source() {
+        ClockSource::Tick => timer::frequency() as u64,
+        ClockSource::Hpet => FEMTOSECONDS_PER_SECOND / HPET_PERIOD_FS.load(Ordering::Relaxed),
+        ClockSource::Tsc => TSC_FREQUENCY.load(Ordering::Relaxed),
+    }
+}
+
+
+/// Returns monotonic time (in ns) since the clock source was selected.
+pub fn now() -> u64 {
+    let base = COUNTER_BASE.load(Ordering::Relaxed);
+    match source() {
+        ClockSource::Tick => timer::tick_time_ns(),
+        ClockSource::Hpet => {
+            let elapsed = hpet_read(HPET_MAIN_COUNTER) - base;
+            (elapsed as u128 * HPET_PERIOD_FS.load(Ordering::Relaxed) as u128 / 1_000_000) as u64
+        }
+        ClockSource::Tsc => {
+            let elapsed = read_tsc() - base;
+            (elapsed as u128 * 1_000_000_000 / TSC_FREQUENCY.load(Ordering::Relaxed) as u128) as u64
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use crate::multiboot::{self, Tag};
+
+
+/// Returns kernel command line passed by bootloader (empty if there is none).
+pub fn cmdline() -> &'static str {
+    multiboot::boot_info()
+        .and_then(|mbi| mbi
+            .filter_map(|tag| if let Tag::BootCommandLine(cmd) = tag { Some(cmd) } else { None })
+            .next())
+        .and_then(|cmd| core::str::from_utf8(cmd).ok())
+        .map(|cmd| cmd.trim_end_matches('\0').trim())
+        .unwrap_or("")
+}
+
+
+/// Returns value of `key=value` option from kernel command line.
+pub fn option(key: &str) -> Option<&'static str> {
+    cmdline()
+        .split_ascii_whitespace()
+        .filter_map(|opt| opt.split_once('='))
+        .find(|&(k, _)| k == key)
+        .map(|(_, value)| value)
+}
@@ -0,0 +1,246 @@
+use core::fmt;
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use crate::{gdt, stack};
+
+/// Number of vectors reserved for CPU exceptions.
+pub const EXCEPTION_COUNT: usize = 32;
+
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+
+/// Names of CPU exceptions, indexed by vector.
+const EXCEPTION_NAMES: [&str; EXCEPTION_COUNT] = [
+    "Divide Error",
+    "Debug",
+    "Non-maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+
+// Entry stubs of all exception vectors. CPU pushes error code only for some exceptions, stubs
+// of the others push zero instead, so every exception ends up with the same frame layout.
+// Common part saves general purpose registers and passes the whole frame to
+// `exception_dispatch`. Stack is 16 byte aligned at the call (CPU aligns it before pushing
+// 5 qwords of interrupt frame, 2 qwords of vector and error code and 15 registers follow).
+macro_rules! exception_stubs {
+    ($($vector:literal $kind:ident),* $(,)?) => {
+        core::arch::global_asm!(
+            $(
+                concat!(".global exception_stub_", $vector),
+                concat!("exception_stub_", $vector, ":"),
+                exception_stubs!(@push $kind),
+                concat!("push ", $vector),
+                "jmp exception_common",
+            )*
+            ".section .rodata",
+            ".global exception_stub_table",
+            "exception_stub_table:",
+            $( concat!(".quad exception_stub_", $vector), )*
+            ".text",
+        );
+    };
+    (@push err) => { "" };
+    (@push noerr) => { "push 0" };
+}
+
+exception_stubs!(
+    0 noerr, 1 noerr, 2 noerr, 3 noerr, 4 noerr, 5 noerr, 6 noerr, 7 noerr,
+    8 err, 9 noerr, 10 err, 11 err, 12 err, 13 err, 14 err, 15 noerr,
+    16 noerr, 17 err, 18 noerr, 19 noerr, 20 noerr, 21 err, 22 noerr, 23 noerr,
+    24 noerr, 25 noerr, 26 noerr, 27 noerr, 28 noerr, 29 err, 30 err, 31 noerr,
+);
+
+core::arch::global_asm!(
+    "exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call exception_dispatch",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // drop vector and error code
+    "add rsp, 16",
+    "iretq",
+);
+
+unsafe extern "C" {
+    static exception_stub_table: [u64; EXCEPTION_COUNT];
+}
+
+
+/// General purpose registers saved by exception entry stub.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SavedRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+
+/// Stack contents at the time `exception_dispatch` is called.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+    pub registers: SavedRegisters,
+    pub vector: u64,
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl ExceptionFrame {
+    /// Returns name of the exception.
+    pub fn name(&self) -> &'static str {
+        EXCEPTION_NAMES.get(self.vector as usize).copied().unwrap_or("Unknown")
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = &self.registers;
+        let s = &self.stack_frame;
+        writeln!(f, "EXCEPTION: {} (vector {})", self.name(), self.vector)?;
+        if self.vector == PAGE_FAULT {
+            writeln!(f, "error code {:#x} {:?}", self.error_code,
+                PageFaultErrorCode::from_bits_truncate(self.error_code))?;
+        } else {
+            writeln!(f, "error code {:#x}", self.error_code)?;
+        }
+        writeln!(f, "RIP {:#018x} CS  {:#06x} RFLAGS {:#018x}",
+            s.instruction_pointer.as_u64(), s.code_segment.0, s.cpu_flags.bits())?;
+        writeln!(f, "RSP {:#018x} SS  {:#06x}", s.stack_pointer.as_u64(), s.stack_segment.0)?;
+        writeln!(f, "CR0 {:#018x} CR2 {:#018x}", Cr0::read_raw(), Cr2::read_raw())?;
+        writeln!(f, "CR3 {:#018x} CR4 {:#018x}", Cr3::read_raw().0.start_address().as_u64(), Cr4::read_raw())?;
+        writeln!(f, "RAX {:#018x} RBX {:#018x} RCX {:#018x}", r.rax, r.rbx, r.rcx)?;
+        writeln!(f, "RDX {:#018x} RSI {:#018x} RDI {:#018x}", r.rdx, r.rsi, r.rdi)?;
+        writeln!(f, "RBP {:#018x} R8  {:#018x} R9  {:#018x}", r.rbp, r.r8, r.r9)?;
+        writeln!(f, "R10 {:#018x} R11 {:#018x} R12 {:#018x}", r.r10, r.r11, r.r12)?;
+        write!(f, "R13 {:#018x} R14 {:#018x} R15 {:#018x}", r.r13, r.r14, r.r15)
+    }
+}
+
+
+/// Installs handlers of all CPU exceptions into given IDT.
+pub fn install_handlers(idt: &mut InterruptDescriptorTable) {
+    let stub = |vector: usize| VirtAddr::new(unsafe { exception_stub_table[vector] });
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(0));
+        idt.debug.set_handler_addr(stub(1));
+        idt.non_maskable_interrupt.set_handler_addr(stub(2));
+        idt.breakpoint.set_handler_addr(stub(3));
+        idt.overflow.set_handler_addr(stub(4));
+        idt.bound_range_exceeded.set_handler_addr(stub(5));
+        idt.invalid_opcode.set_handler_addr(stub(6));
+        idt.device_not_available.set_handler_addr(stub(7));
+        idt.double_fault
+            .set_handler_addr(stub(8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub(10));
+        idt.segment_not_present.set_handler_addr(stub(11));
+        idt.stack_segment_fault.set_handler_addr(stub(12));
+        idt.general_protection_fault.set_handler_addr(stub(13));
+        idt.page_fault.set_handler_addr(stub(14));
+        idt.x87_floating_point.set_handler_addr(stub(16));
+        idt.alignment_check.set_handler_addr(stub(17));
+        idt.machine_check.set_handler_addr(stub(18));
+        idt.simd_floating_point.set_handler_addr(stub(19));
+        idt.virtualization.set_handler_addr(stub(20));
+        idt.cp_protection_exception.set_handler_addr(stub(21));
+        idt.hv_injection_exception.set_handler_addr(stub(28));
+        idt.vmm_communication_exception.set_handler_addr(stub(29));
+        idt.security_exception.set_handler_addr(stub(30));
+    }
+}
+
+
+/// Common handler of all CPU exceptions, called from entry stubs. Breakpoint returns back to
+/// the interrupted code, every other exception is fatal.
+#[unsafe(no_mangle)]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        BREAKPOINT => return,
+        PAGE_FAULT | DOUBLE_FAULT => {
+            // overflowing stack faults on its guard page; if pushing page fault frame faults
+            // again, double fault is raised
+            let overflowed = stack::find_overflowed_stack(Cr2::read_raw())
+                .or_else(|| stack::find_overflowed_stack(frame.stack_frame.stack_pointer.as_u64()));
+            if let Some(stack) = overflowed {
+                panic!("KERNEL STACK OVERFLOW ({} stack, {} KiB)\n{}", stack.name, stack.size() / 1024, frame);
+            }
+        }
+        _ => {}
+    }
+    panic!("{}", frame);
+}
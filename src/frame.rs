@@ -0,0 +1,443 @@
+use x86_64::structures::paging as Paging;
+use x86_64::PhysAddr;
+
+use crate::multiboot::{MemoryMapEntry, MemoryMapType, Multiboot2, Tag, ELF_SECTION_ALLOC};
+use crate::paging;
+
+
+/// Size of a single (normal) physical memory frame.
+pub const FRAME_SIZE: u64 = 4096;
+/// Number of normal frames making up one huge (2 MiB) frame.
+pub const FRAMES_PER_HUGE_FRAME: usize = 512;
+/// Maximum number of reserved physical memory regions the allocator can keep track of.
+pub const MAX_RESERVED_REGIONS: usize = 32;
+
+/// The bitmap is filled before all physical memory is mapped, so it has to lie within the first
+/// GiB mapped by boot code.
+const BITMAP_PLACEMENT_LIMIT: u64 = 1024 * 1024 * 1024;
+
+unsafe extern "C" {
+    // defined in linker/link.ld
+    static _kernel_start: u8;
+    static _kernel_end: u8;
+}
+
+
+/// Global physical frame allocator. Has no frames until `init` places the bitmap into usable
+/// memory and marks that memory as free.
+static FRAME_ALLOCATOR: spin::Mutex<BitmapFrameAllocator> = spin::Mutex::new(BitmapFrameAllocator::new());
+
+
+/// Physical memory manager tracking every 4 KiB frame by a single bit.
+pub struct BitmapFrameAllocator {
+    /// Physical address of the bitmap, one bit per frame up to `frame_limit` - set bit means the
+    /// frame is free.
+    bitmap_addr: u64,
+    /// Number of frames covered by usable memory (index of the highest usable frame + 1).
+    frame_limit: usize,
+    /// Number of frames marked usable during initialisation.
+    usable_frames: usize,
+    /// Number of frames currently free.
+    free_frames: usize,
+    /// Bitmap word from which the search for single free frame starts.
+    next_word: usize,
+    /// Physical memory regions which must never be handed out.
+    reserved: ReservedRegions,
+    /// Whether memory map was already loaded.
+    initialised: bool,
+}
+
+
+/// What a reserved physical memory region is used for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReservedKind {
+    /// Kernel image as laid out by the linker script.
+    KernelImage,
+    /// Loaded section of the kernel ELF image (from multiboot `ElfSymbols` tag).
+    KernelSection,
+    /// Multiboot boot information structure.
+    BootInfo,
+    /// Boot module loaded by the bootloader.
+    BootModule,
+    /// Bitmap of the frame allocator itself.
+    FrameBitmap,
+}
+
+
+/// Physical memory region `[start, end)` excluded from frame allocation.
+#[derive(Clone, Copy, Debug)]
+pub struct ReservedRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: ReservedKind,
+}
+
+
+/// Fixed-size registry of reserved regions (there is no heap yet when it is filled).
+#[derive(Clone, Copy, Debug)]
+pub struct ReservedRegions {
+    regions: [ReservedRegion; MAX_RESERVED_REGIONS],
+    len: usize,
+}
+
+
+/// Zero-sized handle to the global frame allocator, usable wherever `x86_64` expects
+/// `FrameAllocator` or `FrameDeallocator`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelFrameAllocator;
+
+
+impl BitmapFrameAllocator {
+    const fn new() -> Self {
+        Self {
+            bitmap_addr: 0,
+            frame_limit: 0,
+            usable_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+            reserved: ReservedRegions::new(),
+            initialised: false,
+        }
+    }
+
+    /// Places the bitmap into available memory, sized to cover all of it, and marks every whole
+    /// frame inside available memory map regions as free.
+    fn init(&mut self, mem_map: &[MemoryMapEntry]) {
+        let available = || mem_map.iter().filter(|m| m.typ == MemoryMapType::Available);
+        self.frame_limit = available()
+            .map(|m| ((m.base_addr + m.length) / FRAME_SIZE) as usize)
+            .max()
+            .unwrap_or(0);
+        let bitmap_size = (self.frame_limit.div_ceil(64) * 8) as u64;
+        self.bitmap_addr = self.find_bitmap_place(mem_map, bitmap_size)
+            .expect("no memory for physical frame bitmap!");
+        if !self.reserved.push(ReservedRegion {
+            start: self.bitmap_addr,
+            end: self.bitmap_addr + bitmap_size,
+            kind: ReservedKind::FrameBitmap,
+        }) {
+            panic!("reserved region registry is full!");
+        }
+        self.bitmap_mut().fill(0);
+
+        for entry in available() {
+            // only whole frames can be used, so round start up and end down
+            let start = entry.base_addr.div_ceil(FRAME_SIZE) as usize;
+            let end = ((entry.base_addr + entry.length) / FRAME_SIZE) as usize;
+            // frame 0 is never handed out, so physical null pointer stays invalid
+            for idx in start.max(1)..end {
+                if !self.is_free(idx) {
+                    self.set_free(idx);
+                    self.usable_frames += 1;
+                    self.free_frames += 1;
+                }
+            }
+        }
+        // carve out everything registered before the memory map was known
+        let reserved = self.reserved;
+        for region in reserved.iter() {
+            self.claim_region(region.start, region.end);
+        }
+        self.initialised = true;
+    }
+
+    /// Finds frame aligned place for `size` bytes in available memory below
+    /// `BITMAP_PLACEMENT_LIMIT`, which does not overlap any reserved region.
+    fn find_bitmap_place(&self, mem_map: &[MemoryMapEntry], size: u64) -> Option<u64> {
+        let size = size.next_multiple_of(FRAME_SIZE);
+        for entry in mem_map.iter().filter(|m| m.typ == MemoryMapType::Available) {
+            let end = (entry.base_addr + entry.length).min(BITMAP_PLACEMENT_LIMIT);
+            let mut start = entry.base_addr.max(FRAME_SIZE).next_multiple_of(FRAME_SIZE);
+            while start + size <= end {
+                match self.reserved.iter().find(|r| r.start < start + size && start < r.end) {
+                    Some(region) => start = region.end.next_multiple_of(FRAME_SIZE),
+                    None => return Some(start),
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the bitmap, accessed through the current physical memory offset.
+    fn bitmap(&self) -> &[u64] {
+        if self.frame_limit == 0 {
+            return &[];
+        }
+        let ptr = paging::phys_to_virt(self.bitmap_addr).as_ptr();
+        unsafe { core::slice::from_raw_parts(ptr, self.frame_limit.div_ceil(64)) }
+    }
+
+    fn bitmap_mut(&mut self) -> &mut [u64] {
+        if self.frame_limit == 0 {
+            return &mut [];
+        }
+        let ptr = paging::phys_to_virt(self.bitmap_addr).as_mut_ptr();
+        unsafe { core::slice::from_raw_parts_mut(ptr, self.frame_limit.div_ceil(64)) }
+    }
+
+    /// Adds region to the reserved region registry. If the memory map was already loaded, frames
+    /// of the region are removed from the pool immediately.
+    fn reserve(&mut self, region: ReservedRegion) -> bool {
+        if region.start >= region.end || !self.reserved.push(region) {
+            return false;
+        }
+        if self.initialised {
+            self.claim_region(region.start, region.end);
+        }
+        true
+    }
+
+    /// Permanently removes all frames touching `[start, end)` from the pool of usable frames.
+    fn claim_region(&mut self, start: u64, end: u64) {
+        // partially covered frames are reserved too, so round outwards
+        let first = (start / FRAME_SIZE) as usize;
+        let last = (end.div_ceil(FRAME_SIZE) as usize).min(self.frame_limit);
+        for idx in first..last {
+            if self.is_free(idx) {
+                self.set_used(idx);
+                self.usable_frames -= 1;
+                self.free_frames -= 1;
+            }
+        }
+    }
+
+    fn is_free(&self, idx: usize) -> bool {
+        self.bitmap()[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn set_free(&mut self, idx: usize) {
+        self.bitmap_mut()[idx / 64] |= 1 << (idx % 64);
+    }
+
+    fn set_used(&mut self, idx: usize) {
+        self.bitmap_mut()[idx / 64] &= !(1 << (idx % 64));
+    }
+
+    /// Finds and claims single free frame, returning its index.
+    fn allocate_one(&mut self) -> Option<usize> {
+        let words = self.frame_limit.div_ceil(64);
+        // start at the hint, wrapping around to the beginning of the bitmap
+        for w in (self.next_word..words).chain(0..self.next_word) {
+            let word = self.bitmap()[w];
+            if word != 0 {
+                let idx = w * 64 + word.trailing_zeros() as usize;
+                self.set_used(idx);
+                self.free_frames -= 1;
+                self.next_word = w;
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    /// Finds and claims `count` physically contiguous free frames below frame index `limit`,
+    /// with first frame index being multiple of `align` frames. Returns index of the first frame.
+    fn allocate_range(&mut self, count: usize, align: usize, limit: usize) -> Option<usize> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        if count == 1 && align <= 1 && limit >= self.frame_limit {
+            return self.allocate_one();
+        }
+        let align = align.max(1);
+        let mut start = align;
+        while start + count <= self.frame_limit.min(limit) {
+            // skip whole used words quickly
+            if start.is_multiple_of(64) && self.bitmap()[start / 64] == 0 {
+                start = (start + 64).next_multiple_of(align);
+                continue;
+            }
+            if let Some(idx) = (start..start + count).find(|&idx| !self.is_free(idx)) {
+                // the run is broken, continue behind the used frame
+                start = (idx + 1).next_multiple_of(align);
+                continue;
+            }
+            for idx in start..(start + count) {
+                self.set_used(idx);
+            }
+            self.free_frames -= count;
+            return Some(start);
+        }
+        None
+    }
+
+    /// Returns `count` frames starting at index `start` back to the allocator. The whole range is
+    /// checked before any frame is freed.
+    fn free_range(&mut self, start: usize, count: usize) {
+        let end = start + count;
+        let (start_addr, end_addr) = (start as u64 * FRAME_SIZE, end as u64 * FRAME_SIZE);
+        if start == 0 || end > self.frame_limit {
+            panic!("invalid free of physical frames {:#x}-{:#x}!", start_addr, end_addr);
+        }
+        if let Some(r) = self.reserved.iter().find(|r| r.start < end_addr && start_addr < r.end) {
+            panic!("free of physical frames {:#x}-{:#x} inside reserved {:?} region!", start_addr, end_addr, r.kind);
+        }
+        if let Some(idx) = (start..end).find(|&idx| self.is_free(idx)) {
+            panic!("double free of physical frame {:#x}!", idx as u64 * FRAME_SIZE);
+        }
+        for idx in start..end {
+            self.set_free(idx);
+        }
+        self.free_frames += count;
+        // freed frames should be reused first
+        self.next_word = self.next_word.min(start / 64);
+    }
+}
+
+
+impl ReservedRegions {
+    const fn new() -> Self {
+        Self {
+            regions: [ReservedRegion { start: 0, end: 0, kind: ReservedKind::KernelImage }; MAX_RESERVED_REGIONS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, region: ReservedRegion) -> bool {
+        if self.len == MAX_RESERVED_REGIONS {
+            return false;
+        }
+        self.regions[self.len] = region;
+        self.len += 1;
+        true
+    }
+
+    /// Iterates over all registered regions.
+    pub fn iter(&self) -> impl Iterator<Item = &ReservedRegion> {
+        self.regions[..self.len].iter()
+    }
+}
+
+
+/// Registers physical memory region, which must never be returned by the frame allocator.
+/// Returns false if the region is empty or the registry is full.
+pub fn reserve_region(start: u64, end: u64, kind: ReservedKind) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().reserve(ReservedRegion { start, end, kind })
+    })
+}
+
+
+/// Registers regions occupied by the kernel image, multiboot information structure and boot
+/// modules, so frames holding live kernel code or boot data are never allocated.
+pub fn reserve_boot_regions(multiboot_addr: usize) {
+    // kernel image boundaries exported by the linker script (higher half addresses)
+    let kernel_start = paging::kernel_virt_to_phys(&raw const _kernel_start as u64);
+    let kernel_end = paging::kernel_virt_to_phys(&raw const _kernel_end as u64);
+    if !reserve_region(kernel_start, kernel_end, ReservedKind::KernelImage) {
+        panic!("failed to reserve kernel image!");
+    }
+
+    let mbi = Multiboot2::from_ptr(paging::phys_to_virt(multiboot_addr as u64).as_ptr());
+    let mbi_end = multiboot_addr as u64 + mbi.total_size as u64;
+    if !reserve_region(multiboot_addr as u64, mbi_end, ReservedKind::BootInfo) {
+        panic!("failed to reserve multiboot information!");
+    }
+
+    for tag in mbi {
+        let ok = match tag {
+            Tag::ElfSymbols(sections) => sections
+                .filter(|s| s.flags & ELF_SECTION_ALLOC != 0 && s.size != 0)
+                // section addresses are virtual
+                .map(|s| (paging::kernel_virt_to_phys(s.addr), s.size))
+                // sections lying within kernel image are already covered
+                .filter(|&(addr, size)| addr < kernel_start || addr + size > kernel_end)
+                .all(|(addr, size)| reserve_region(addr, addr + size, ReservedKind::KernelSection)),
+            Tag::Modules { mod_start, mod_end, .. } => {
+                reserve_region(mod_start as u64, mod_end as u64, ReservedKind::BootModule)
+            },
+            _ => true,
+        };
+        if !ok {
+            panic!("reserved region registry is full!");
+        }
+    }
+}
+
+
+/// Initialise global frame allocator from memory map provided by bootloader. Regions registered
+/// with `reserve_region` are excluded.
+pub fn init(mem_map: &[MemoryMapEntry]) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().init(mem_map);
+    });
+}
+
+
+/// Allocates `count` physically contiguous normal frames, returning the first one.
+pub fn allocate_frames(count: usize) -> Option<Paging::PhysFrame> {
+    let idx = x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().allocate_range(count, 1, usize::MAX)
+    })?;
+    Some(Paging::PhysFrame::containing_address(PhysAddr::new(idx as u64 * FRAME_SIZE)))
+}
+
+
+/// Allocates single normal frame lying below physical address `limit`.
+pub fn allocate_frame_below(limit: u64) -> Option<Paging::PhysFrame> {
+    let idx = x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().allocate_range(1, 1, (limit / FRAME_SIZE) as usize)
+    })?;
+    Some(Paging::PhysFrame::containing_address(PhysAddr::new(idx as u64 * FRAME_SIZE)))
+}
+
+
+/// Allocates single huge (2 MiB) frame, aligned to its size.
+pub fn allocate_huge_frame() -> Option<Paging::PhysFrame<Paging::Size2MiB>> {
+    let idx = x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().allocate_range(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME, usize::MAX)
+    })?;
+    Some(Paging::PhysFrame::containing_address(PhysAddr::new(idx as u64 * FRAME_SIZE)))
+}
+
+
+/// Returns `count` contiguous normal frames starting at `frame` to the allocator.
+///
+/// SAFETY: frames must have been allocated by this allocator and must not be used anymore.
+pub unsafe fn deallocate_frames(frame: Paging::PhysFrame, count: usize) {
+    let idx = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().free_range(idx, count);
+    });
+}
+
+
+/// Returns number of frames, which are currently free.
+pub fn free_frames() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().free_frames)
+}
+
+
+/// Returns number of frames, which were usable after initialisation.
+pub fn usable_frames() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().usable_frames)
+}
+
+
+unsafe impl Paging::FrameAllocator<Paging::Size4KiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Paging::PhysFrame> {
+        allocate_frames(1)
+    }
+}
+
+
+unsafe impl Paging::FrameAllocator<Paging::Size2MiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Paging::PhysFrame<Paging::Size2MiB>> {
+        allocate_huge_frame()
+    }
+}
+
+
+impl Paging::FrameDeallocator<Paging::Size4KiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: Paging::PhysFrame) {
+        unsafe { deallocate_frames(frame, 1) };
+    }
+}
+
+
+impl Paging::FrameDeallocator<Paging::Size2MiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: Paging::PhysFrame<Paging::Size2MiB>) {
+        let frame = Paging::PhysFrame::containing_address(frame.start_address());
+        unsafe { deallocate_frames(frame, FRAMES_PER_HUGE_FRAME) };
+    }
+}
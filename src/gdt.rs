@@ -0,0 +1,71 @@
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::stack;
+
+/// Index of Interrupt Stack Table entry used by double fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        // stack loaded by the CPU when an interrupt arrives while running in ring 3
+        tss.privilege_stack_table[0] = stack::privilege_stack_pointer();
+        // double fault handler gets its own stack, so stack overflow can be reported
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack::double_fault_stack_pointer();
+        tss
+    };
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        // order of segments follows the layout expected by SYSCALL/SYSRET instructions
+        let kernel_code = gdt.append(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.append(Descriptor::kernel_data_segment());
+        let user_data = gdt.append(Descriptor::user_data_segment());
+        let user_code = gdt.append(Descriptor::user_code_segment());
+        let tss = gdt.append(Descriptor::tss_segment(&TSS));
+        (gdt, Selectors { kernel_code, kernel_data, user_code, user_data, tss })
+    };
+}
+
+
+/// Segment selectors of the kernel GDT.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+
+/// Loads kernel GDT, replacing the minimal one set up by boot code, reloads all segment
+/// registers and loads the TSS.
+pub fn init() {
+    GDT.0.load();
+    let selectors = &GDT.1;
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        FS::set_reg(selectors.kernel_data);
+        GS::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+}
+
+
+/// Returns segment selectors of the kernel GDT.
+#[allow(unused)]
+pub fn selectors() -> Selectors {
+    GDT.1
+}
@@ -1,23 +1,69 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{
     InterruptDescriptorTable,
     InterruptStackFrame,
-    PageFaultErrorCode
 };
 
-use crate::{keyboard::Key, pic::{end_of_interrupt, IRQ}, vga_printf};
+use crate::{apic, exceptions, pic::{self, IRQ, IRQ_LINES}};
+
+/// Maximum number of handlers sharing single IRQ line.
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// Handler of an IRQ. End of interrupt is sent automatically after all handlers of the line
+/// are called.
+pub type IrqHandler = fn();
+
+/// Registered IRQ handlers, indexed by IRQ line.
+static IRQ_HANDLERS: spin::Mutex<[[Option<IrqHandler>; MAX_SHARED_HANDLERS]; IRQ_LINES]> =
+    spin::Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_LINES]);
+
+/// Number of spurious IRQs received so far.
+static SPURIOUS_IRQS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Generates IDT entry point of given IRQ, forwarding it to `dispatch_irq`.
+macro_rules! irq_entry {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_irq($irq);
+        }
+    };
+}
+
+irq_entry!(irq_entry_0, IRQ::Timer);
+irq_entry!(irq_entry_1, IRQ::Keyboard);
+irq_entry!(irq_entry_2, IRQ::Cascade);
+irq_entry!(irq_entry_3, IRQ::COM2);
+irq_entry!(irq_entry_4, IRQ::COM1);
+irq_entry!(irq_entry_5, IRQ::LPT2);
+irq_entry!(irq_entry_6, IRQ::FloppyDisk);
+irq_entry!(irq_entry_7, IRQ::LPT1);
+irq_entry!(irq_entry_8, IRQ::CMOSClock);
+irq_entry!(irq_entry_9, IRQ::Nic1);
+irq_entry!(irq_entry_10, IRQ::Nic2);
+irq_entry!(irq_entry_11, IRQ::Nic3);
+irq_entry!(irq_entry_12, IRQ::Mouse);
+irq_entry!(irq_entry_13, IRQ::Coprocessor);
+irq_entry!(irq_entry_14, IRQ::PrimaryATA);
+irq_entry!(irq_entry_15, IRQ::SecondaryATA);
 
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[IRQ::Timer as u8].set_handler_fn(timer_interrupt);
-        idt[IRQ::Keyboard as u8].set_handler_fn(keyboard_interrupt);
-        idt[IRQ::PrimaryATA as u8].set_handler_fn(ata_prim_handler);
-        idt[IRQ::SecondaryATA as u8].set_handler_fn(ata_sec_handler);
+        exceptions::install_handlers(&mut idt);
+        let entries: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [
+            irq_entry_0, irq_entry_1, irq_entry_2, irq_entry_3,
+            irq_entry_4, irq_entry_5, irq_entry_6, irq_entry_7,
+            irq_entry_8, irq_entry_9, irq_entry_10, irq_entry_11,
+            irq_entry_12, irq_entry_13, irq_entry_14, irq_entry_15,
+        ];
+        for (irq, entry) in IRQ::ALL.into_iter().zip(entries) {
+            idt[irq.vector()].set_handler_fn(entry);
+        }
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(apic_spurious_interrupt);
         idt
     };
 }
@@ -27,47 +73,83 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn ata_sec_handler(_stack_frame: InterruptStackFrame) {
-    end_of_interrupt(IRQ::SecondaryATA);
-}
 
-extern "x86-interrupt" fn ata_prim_handler(_stack_frame: InterruptStackFrame) {
-    end_of_interrupt(IRQ::PrimaryATA);
+/// Registers handler of given IRQ and unmasks the IRQ line. Handlers of shared lines are called
+/// in order of registration. Returns false if the line already has `MAX_SHARED_HANDLERS`
+/// handlers.
+pub fn register_irq(irq: IRQ, handler: IrqHandler) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        match handlers[irq.line() as usize].iter_mut().find(|h| h.is_none()) {
+            Some(slot) => {
+                *slot = Some(handler);
+                // controller locks are taken by interrupts as well, interrupts must stay disabled
+                unmask(irq);
+                true
+            }
+            None => false,
+        }
+    })
 }
 
 
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
+/// Switches interrupt delivery from the 8259 PIC to the APIC, if the system has one. Lines with
+/// registered handlers stay unmasked. Returns whether APIC is used.
+pub fn init_apic() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if !apic::init() {
+            return false;
+        }
+        let handlers = IRQ_HANDLERS.lock();
+        for irq in IRQ::ALL {
+            if handlers[irq.line() as usize].iter().any(|h| h.is_some()) {
+                apic::unmask(irq);
+            }
+        }
+        true
+    })
 }
 
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, err: PageFaultErrorCode) {
-    // Control Register 2 stores address that was accessed and caused a page fault
-    let cr2 = {
-        x86_64::registers::control::Cr2::read()
-    };
-    vga_printf!("PAGE FAULT CAUSED BY {:?}, error {:?}\n", cr2, err);
-    vga_printf!("Stack trace : {:?}", stack_frame);
-    loop {}
+fn unmask(irq: IRQ) {
+    if apic::is_enabled() {
+        apic::unmask(irq);
+    } else {
+        pic::unmask(irq);
+    }
 }
 
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> !
-{
-    panic!("EXCEPTION: DOUBLE FAULT {} :: {:#?}", error_code, stack_frame);
+/// Returns number of spurious IRQs received so far.
+#[allow(unused)]
+pub fn spurious_irqs() -> usize {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
 }
 
 
-extern "x86-interrupt" fn timer_interrupt(_stack_frame: InterruptStackFrame) {
-    // vga_print_char(b'.');
-    end_of_interrupt(IRQ::Timer);
+/// Calls all handlers registered for given IRQ and acknowledges it.
+fn dispatch_irq(irq: IRQ) {
+    let apic = apic::is_enabled();
+    // stray PIC vectors are not acknowledged, EOI would end another interrupt
+    let spurious = if apic { !apic::is_in_service(irq.vector()) } else { pic::is_spurious(irq) };
+    if spurious {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // handlers are copied out, so they can (un)register handlers themselves
+    let handlers = IRQ_HANDLERS.lock()[irq.line() as usize];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+    if apic {
+        apic::end_of_interrupt();
+    } else {
+        pic::end_of_interrupt(irq);
+    }
 }
 
 
-extern "x86-interrupt" fn keyboard_interrupt(_stack_frame: InterruptStackFrame) {
-    let scancode: u8 = unsafe {
-        let mut p = x86_64::instructions::port::Port::new(0x60);
-        p.read()
-    };
-    crate::keyboard::_push_key(scancode);
-    end_of_interrupt(IRQ::Keyboard);
+/// Local APIC spurious interrupt, must not be acknowledged.
+extern "x86-interrupt" fn apic_spurious_interrupt(_stack_frame: InterruptStackFrame) {
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
 }
@@ -1,6 +1,17 @@
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
 
+use crossbeam_queue::ArrayQueue;
 use lazy_static::lazy_static;
 
+use crate::asyn::{AtomicWaker, Stream};
+use crate::keymap::{self, DeadKey};
+use crate::{interrupts, pic::IRQ, ps2};
+
+/// Data port of the PS/2 controller.
+const KEYBOARD_DATA_PORT: u16 = 0x60;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Key {
     Backspace,
@@ -10,136 +21,402 @@ pub enum Key {
     LeftShift,
     RightShift,
     LeftCtrl,
-    // RightCtrl,
-    CapsLock,
+    RightCtrl,
     LeftAlt,
-    // RightAlt,
+    RightAlt,
+    LeftGui,
+    RightGui,
+    Menu,
+    CapsLock,
+    NumLock,
+    ScrollLock,
     Up,
     Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    PrintScreen,
+    Pause,
+    /// Function key F1 - F12.
+    F(u8),
+    /// Printable character (code page 437) of the current layout, with shift, caps lock and
+    /// dead keys applied.
     Char(u8),
+    /// Dead key, its accent is applied to the next typed character.
+    Dead(DeadKey),
+    /// ASCII control character (0x00 - 0x1F), typed as Ctrl with a letter or one of `@[\]^_`.
+    Control(u8),
+}
+
+/// State of modifier and lock keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+#[allow(unused)]
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    /// Returns keyboard LEDs reflecting lock keys (see `ps2::set_leds`).
+    pub fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.caps_lock {
+            leds |= ps2::LED_CAPS_LOCK;
+        }
+        if self.num_lock {
+            leds |= ps2::LED_NUM_LOCK;
+        }
+        if self.scroll_lock {
+            leds |= ps2::LED_SCROLL_LOCK;
+        }
+        leds
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct KeyState {
     pub key: Key,
     pub state: bool,
+    /// Modifiers after this key event was applied.
+    #[allow(unused)]
+    pub modifiers: Modifiers,
 }
 
+/// Maximum number of scancodes waiting for decoding.
+pub const SCANCODE_QUEUE_SIZE: usize = 128;
+
+/// Scancode prefix of extended keys.
+const EXTENDED_PREFIX: u8 = 0xE0;
+/// Scancode prefix of the Pause key, followed by two more bytes.
+const PAUSE_PREFIX: u8 = 0xE1;
+
 lazy_static! {
-    static ref KEY_BUFFER: spin::Mutex<[u8;2]> = spin::Mutex::new([0u8;2]);
+    /// Scancodes received by the keyboard interrupt, waiting for `translate_key`.
+    static ref SCANCODE_QUEUE: ArrayQueue<u8> = ArrayQueue::new(SCANCODE_QUEUE_SIZE);
+}
+
+/// Number of scancodes dropped because the queue was full.
+static DROPPED_SCANCODES: AtomicU64 = AtomicU64::new(0);
+/// Waker of the task reading `KeyStream`, woken by the keyboard interrupt.
+static KEY_WAKER: AtomicWaker = AtomicWaker::new();
+/// Decoder state, only used by the task reading key events.
+static DECODER: spin::Mutex<Decoder> = spin::Mutex::new(Decoder {
+    extended: false,
+    pause_bytes: 0,
+    dead_key: None,
+    modifiers: Modifiers {
+        left_shift: false,
+        right_shift: false,
+        left_ctrl: false,
+        right_ctrl: false,
+        left_alt: false,
+        right_alt: false,
+        caps_lock: false,
+        num_lock: false,
+        scroll_lock: false,
+    },
+    held_locks: [false; 3],
+    pending_leds: None,
+});
+
+/// Registers keyboard IRQ handler. The scancode queue is allocated on the heap, so this must
+/// be called after the heap is initialised.
+pub fn init() {
+    lazy_static::initialize(&SCANCODE_QUEUE);
+    if !interrupts::register_irq(IRQ::Keyboard, keyboard_irq) {
+        panic!("failed to register keyboard IRQ handler!");
+    }
 }
 
-pub fn has_key_event() -> bool {
-    let lock = KEY_BUFFER.lock();
-    lock[0] != 0 || lock[1] != 0
+fn keyboard_irq() {
+    // data may have been taken already by the PS/2 driver
+    if !ps2::has_data() {
+        return;
+    }
+    let scancode = crate::port::input_byte(KEYBOARD_DATA_PORT);
+    _push_key(scancode);
+    KEY_WAKER.wake();
 }
 
+/// Appends scancode to the queue, counting it as dropped if the queue is full.
 pub fn _push_key(c: u8) {
-    let mut lock = KEY_BUFFER.lock();
-    if lock[0] == 0xe0 {
-        lock[1] = c;
-    }
-    else {
-        lock[0] = c;
+    if SCANCODE_QUEUE.push(c).is_err() {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+/// Returns number of scancodes lost because they arrived while the queue was full.
+pub fn dropped_scancodes() -> u64 {
+    DROPPED_SCANCODES.load(Ordering::Relaxed)
+}
+
+
+/// Returns current state of modifier and lock keys.
+#[allow(unused)]
+pub fn modifiers() -> Modifiers {
+    DECODER.lock().modifiers
+}
 
-/// Translates the content of scancode buffer into key state.
+
+/// Decodes queued scancodes into the next key state. Scancodes of unknown keys are consumed
+/// and skipped, `None` is returned once the queue is empty.
 pub fn translate_key() -> Option<KeyState> {
-    let mut lock = KEY_BUFFER.lock();
-    let k = match lock.as_slice() {
-        // we AND with 0x7F, which will disable the most significant bit (press / release
-        // indication)
-        &[0xe0, x] => match x & 0x7f {
-            // escaped key not registered, return without further action
-            0x00 => return None,
-            // TODO match escaped characters
-            0x48 => Some(Key::Up),    // Up arrow
-            0x50 => Some(Key::Down),  // Down arrow
-            _ => None,
-        },
-        // we AND with 0x7F, which will disable the most significant bit (press / release
-        // indication)
-        &[x, _] => match x & 0x7f {
-            // NOTE: SEE FOR COMPLETE SCANCODE MAP:
-            // https://wiki.osdev.org/PS/2_Keyboard#Scan_Code_Set_1
+    let (key, leds) = {
+        let mut decoder = DECODER.lock();
+        let key = core::iter::from_fn(|| SCANCODE_QUEUE.pop()).find_map(|scancode| decoder.decode(scancode));
+        (key, decoder.pending_leds.take())
+    };
+    // LEDs are set by polled keyboard commands, so the decoder must not stay locked meanwhile
+    if let Some(leds) = leds {
+        ps2::set_leds(leds);
+    }
+    key
+}
+
+
+// NOTE: SEE FOR COMPLETE SCANCODE MAP:
+// https://wiki.osdev.org/PS/2_Keyboard#Scan_Code_Set_1
+
+/// Characters of keypad keys 0x47 - 0x53 with num lock on.
+const KEYPAD_CHARS: [u8; 13] = *b"789-456+1230.";
+/// Keys of keypad keys 0x47 - 0x53 with num lock off.
+const KEYPAD_KEYS: [Option<Key>; 13] = [
+    Some(Key::Home), Some(Key::Up), Some(Key::PageUp), Some(Key::Char(b'-')),
+    Some(Key::Left), None, Some(Key::Right), Some(Key::Char(b'+')),
+    Some(Key::End), Some(Key::Down), Some(Key::PageDown), Some(Key::Insert), Some(Key::Delete),
+];
+
+
+/// Scancode set 1 decoder, tracking prefixes and modifier state.
+struct Decoder {
+    /// Set when extended prefix was decoded and the next scancode belongs to it.
+    extended: bool,
+    /// Remaining bytes of the Pause key sequence.
+    pause_bytes: u8,
+    /// Dead key waiting for the next character.
+    dead_key: Option<DeadKey>,
+    modifiers: Modifiers,
+    /// Whether caps lock, num lock and scroll lock keys are held, so auto-repeat of their
+    /// press does not toggle the lock again.
+    held_locks: [bool; 3],
+    /// New state of keyboard LEDs, which still has to be sent to the keyboard.
+    pending_leds: Option<u8>,
+}
+
+impl Decoder {
+    /// Processes single scancode, returning key state once a whole key event is decoded.
+    fn decode(&mut self, scancode: u8) -> Option<KeyState> {
+        if self.pause_bytes > 0 {
+            self.pause_bytes -= 1;
+            // Pause has no release code, press and release are sent at once (E1 1D 45 E1 9D C5)
+            if self.pause_bytes > 0 {
+                return None;
+            }
+            return Some(KeyState { key: Key::Pause, state: scancode & 0x80 == 0x00, modifiers: self.modifiers });
+        }
+        match scancode {
+            EXTENDED_PREFIX => {
+                self.extended = true;
+                return None;
+            }
+            PAUSE_PREFIX => {
+                self.pause_bytes = 2;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = core::mem::take(&mut self.extended);
+        // most significant bit distinguishes release from press
+        let pressed = scancode & 0x80 == 0x00;
+        let mut key = self.scancode_to_key(extended, scancode & 0x7f)?;
+        if let Some(leds) = self.update_modifiers(key, pressed) {
+            self.pending_leds = Some(leds);
+        }
+        if pressed {
+            key = self.apply_dead_key(key);
+        }
+        Some(KeyState { key, state: pressed, modifiers: self.modifiers })
+    }
+
+    /// Updates modifier state by given key event. Returns new state of keyboard LEDs if a lock
+    /// key was toggled.
+    fn update_modifiers(&mut self, key: Key, pressed: bool) -> Option<u8> {
+        let m = &mut self.modifiers;
+        match key {
+            Key::LeftShift => m.left_shift = pressed,
+            Key::RightShift => m.right_shift = pressed,
+            Key::LeftCtrl => m.left_ctrl = pressed,
+            Key::RightCtrl => m.right_ctrl = pressed,
+            Key::LeftAlt => m.left_alt = pressed,
+            Key::RightAlt => m.right_alt = pressed,
+            Key::CapsLock | Key::NumLock | Key::ScrollLock => {
+                let (lock, held) = match key {
+                    Key::CapsLock => (&mut m.caps_lock, &mut self.held_locks[0]),
+                    Key::NumLock => (&mut m.num_lock, &mut self.held_locks[1]),
+                    _ => (&mut m.scroll_lock, &mut self.held_locks[2]),
+                };
+                let toggled = pressed && !*held;
+                *held = pressed;
+                if toggled {
+                    *lock = !*lock;
+                    return Some(self.modifiers.leds());
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Combines pending dead key with the typed key.
+    fn apply_dead_key(&mut self, key: Key) -> Key {
+        match key {
+            Key::Dead(dead) => match self.dead_key.take() {
+                // pressing dead key twice types the accent itself
+                Some(pending) if pending == dead => Key::Char(dead.spacing()),
+                _ => {
+                    self.dead_key = Some(dead);
+                    key
+                }
+            },
+            Key::Char(c) => match self.dead_key.take() {
+                Some(dead) => Key::Char(dead.compose(c)),
+                None => key,
+            },
+            // modifiers keep the dead key pending, other keys cancel it
+            Key::LeftShift | Key::RightShift | Key::LeftCtrl | Key::RightCtrl
+            | Key::LeftAlt | Key::RightAlt | Key::CapsLock => key,
+            _ => {
+                self.dead_key = None;
+                key
+            }
+        }
+    }
+
+    /// Translates set 1 scancode (without press / release bit) into key.
+    fn scancode_to_key(&self, extended: bool, code: u8) -> Option<Key> {
+        if extended {
+            return match code {
+                0x1C => Some(Key::Enter),     // keypad Enter
+                0x1D => Some(Key::RightCtrl),
+                0x35 => Some(Key::Char(b'/')), // keypad /
+                0x37 => Some(Key::PrintScreen),
+                0x38 => Some(Key::RightAlt),
+                0x47 => Some(Key::Home),
+                0x48 => Some(Key::Up),
+                0x49 => Some(Key::PageUp),
+                0x4B => Some(Key::Left),
+                0x4D => Some(Key::Right),
+                0x4F => Some(Key::End),
+                0x50 => Some(Key::Down),
+                0x51 => Some(Key::PageDown),
+                0x52 => Some(Key::Insert),
+                0x53 => Some(Key::Delete),
+                0x5B => Some(Key::LeftGui),
+                0x5C => Some(Key::RightGui),
+                0x5D => Some(Key::Menu),
+                // fake shifts surrounding Print Screen and navigation keys are ignored
+                _ => None,
+            };
+        }
+        let shift = self.modifiers.shift();
+        match code {
             0x01 => Some(Key::Escape),
-            0x02 => Some(Key::Char(b'1')),
-            0x03 => Some(Key::Char(b'2')),
-            0x04 => Some(Key::Char(b'3')),
-            0x05 => Some(Key::Char(b'4')),
-            0x06 => Some(Key::Char(b'5')),
-            0x07 => Some(Key::Char(b'6')),
-            0x08 => Some(Key::Char(b'7')),
-            0x09 => Some(Key::Char(b'8')),
-            0x0A => Some(Key::Char(b'9')),
-            0x0B => Some(Key::Char(b'0')),
-            0x0C => Some(Key::Char(b'-')),
-            0x0D => Some(Key::Char(b'=')),
             0x0E => Some(Key::Backspace),
             0x0F => Some(Key::Tab),
-            0x10 => Some(Key::Char(b'q')),
-            0x11 => Some(Key::Char(b'w')),
-            0x12 => Some(Key::Char(b'e')),
-            0x13 => Some(Key::Char(b'r')),
-            0x14 => Some(Key::Char(b't')),
-            0x15 => Some(Key::Char(b'y')),
-            0x16 => Some(Key::Char(b'u')),
-            0x17 => Some(Key::Char(b'i')),
-            0x18 => Some(Key::Char(b'o')),
-            0x19 => Some(Key::Char(b'p')),
-            0x1A => Some(Key::Char(b'[')),
-            0x1B => Some(Key::Char(b']')),
             0x1C => Some(Key::Enter),
             0x1D => Some(Key::LeftCtrl),
-            0x1E => Some(Key::Char(b'a')),
-            0x1F => Some(Key::Char(b's')),
-            0x20 => Some(Key::Char(b'd')),
-            0x21 => Some(Key::Char(b'f')),
-            0x22 => Some(Key::Char(b'g')),
-            0x23 => Some(Key::Char(b'h')),
-            0x24 => Some(Key::Char(b'j')),
-            0x25 => Some(Key::Char(b'k')),
-            0x26 => Some(Key::Char(b'l')),
-            0x27 => Some(Key::Char(b';')),
-            0x28 => Some(Key::Char(b'\'')),
-            0x29 => Some(Key::Char(b'`')),
             0x2A => Some(Key::LeftShift),
-            0x2B => Some(Key::Char(b'\\')),
-            0x2C => Some(Key::Char(b'z')),
-            0x2D => Some(Key::Char(b'x')),
-            0x2E => Some(Key::Char(b'c')),
-            0x2F => Some(Key::Char(b'v')),
-            0x30 => Some(Key::Char(b'b')),
-            0x31 => Some(Key::Char(b'n')),
-            0x32 => Some(Key::Char(b'm')),
-            0x33 => Some(Key::Char(b',')),
-            0x34 => Some(Key::Char(b'.')),
-            0x35 => Some(Key::Char(b'/')),
             0x36 => Some(Key::RightShift),
-            0x37 => Some(Key::Char(b'*')),
             0x38 => Some(Key::LeftAlt),
-            0x39 => Some(Key::Char(b' ')),
             0x3A => Some(Key::CapsLock),
-            _ => return None,
-        },
-        _ => return None,
-    };
-    let result = if let Some(key) = k {
-        let key_pressed = if lock[0] == 0xe0 {
-            lock[1] & 0x80 == 0x00
-        } else {
-            lock[0] & 0x80 == 0x00
-        };
-        Some(KeyState { key, state: key_pressed })
-    } else {
-        None
-    };
+            0x3B..=0x44 => Some(Key::F(code - 0x3A)),
+            0x45 => Some(Key::NumLock),
+            0x46 => Some(Key::ScrollLock),
+            0x47..=0x53 => {
+                // shift temporarily inverts num lock
+                let index = (code - 0x47) as usize;
+                if self.modifiers.num_lock != shift {
+                    Some(Key::Char(KEYPAD_CHARS[index]))
+                } else {
+                    KEYPAD_KEYS[index]
+                }
+            }
+            0x57 => Some(Key::F(11)),
+            0x58 => Some(Key::F(12)),
+            // character keys, including additional key of 102-key keyboards next to left shift
+            0x00..0x3A | 0x56 => {
+                // right alt acts as AltGr
+                let c = keymap::current().character(code, shift, self.modifiers.right_alt);
+                match DeadKey::from_marker(c) {
+                    Some(dead) => Some(Key::Dead(dead)),
+                    None => (c != 0).then(|| self.character(c)),
+                }
+            }
+            _ => None,
+        }
+    }
 
-    lock[0] = 0x00;
-    lock[1] = 0x00;
+    /// Applies caps lock and ctrl to character of a key.
+    fn character(&self, c: u8) -> Key {
+        // caps lock inverts shift for letters only
+        let c = if self.modifiers.caps_lock && c.is_ascii_alphabetic() { c ^ 0x20 } else { c };
+        match c.to_ascii_uppercase() {
+            b'@'..=b'_' if self.modifiers.ctrl() => Key::Control(c.to_ascii_uppercase() & 0x1F),
+            _ => Key::Char(c),
+        }
+    }
+}
 
-    result
+
+/// Stream of key events for asynchronous tasks. Events are decoded from the scancode queue,
+/// the reading task is woken by the keyboard interrupt. There is a single waker, so only one
+/// task should read key events at a time.
+pub struct KeyStream {
+    _private: (),
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        Self { _private: () }
+    }
 }
 
+impl Stream for KeyStream {
+    type Item = KeyState;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyState>> {
+        if let Some(key) = translate_key() {
+            return Poll::Ready(Some(key));
+        }
+        KEY_WAKER.register(cx.waker());
+        // scancode may have arrived before the waker was registered
+        match translate_key() {
+            Some(key) => Poll::Ready(Some(key)),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{cmdline, vga_printf};
+
+/// Keyboard layout, giving characters of keys with scancodes 0x00 - 0x39 and 0x56 (set 1).
+/// Characters are in code page 437 (shown by the VGA text mode), letters missing in it (e.g.
+/// Slovak and Czech letters with caron) are replaced by their base letter. Values 0x01 - 0x06
+/// mark dead keys (see `DeadKey`), zero marks keys without character.
+pub struct Layout {
+    pub name: &'static str,
+    pub description: &'static str,
+    unshifted: [u8; 0x3A],
+    shifted: [u8; 0x3A],
+    /// Characters typed with AltGr (right alt).
+    altgr: [u8; 0x3A],
+    /// Characters of the additional key of 102-key keyboards (scancode 0x56) - unshifted,
+    /// shifted and with AltGr.
+    extra: [u8; 3],
+}
+
+impl Layout {
+    /// Returns character of key with given scancode (without press / release bit), dead key
+    /// marker or zero. Keys without AltGr character type their normal character.
+    pub fn character(&self, code: u8, shift: bool, altgr: bool) -> u8 {
+        let (normal, with_altgr) = match code {
+            0x56 => (if shift { self.extra[1] } else { self.extra[0] }, self.extra[2]),
+            0x00..0x3A => {
+                let index = code as usize;
+                (if shift { self.shifted[index] } else { self.unshifted[index] }, self.altgr[index])
+            }
+            _ => return 0,
+        };
+        if altgr && with_altgr != 0 { with_altgr } else { normal }
+    }
+}
+
+
+/// Accent typed by a dead key, combined with the next typed character.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeadKey {
+    Acute = 1,
+    Caron = 2,
+    Circumflex = 3,
+    Diaeresis = 4,
+    Grave = 5,
+    Ring = 6,
+}
+
+impl DeadKey {
+    /// Returns dead key marked by given layout table value.
+    pub fn from_marker(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(DeadKey::Acute),
+            2 => Some(DeadKey::Caron),
+            3 => Some(DeadKey::Circumflex),
+            4 => Some(DeadKey::Diaeresis),
+            5 => Some(DeadKey::Grave),
+            6 => Some(DeadKey::Ring),
+            _ => None,
+        }
+    }
+
+    /// Returns the accent itself, typed by the dead key followed by space (or pressed twice).
+    pub fn spacing(self) -> u8 {
+        match self {
+            DeadKey::Acute => b'\'',
+            // code page 437 has no caron
+            DeadKey::Caron => b'^',
+            DeadKey::Circumflex => b'^',
+            DeadKey::Diaeresis => b'"',
+            DeadKey::Grave => b'`',
+            DeadKey::Ring => 0xF8,
+        }
+    }
+
+    /// Returns character `c` with the accent. Combinations missing in code page 437 type just
+    /// the character.
+    pub fn compose(self, c: u8) -> u8 {
+        match (self, c) {
+            (_, b' ') => self.spacing(),
+            (DeadKey::Acute, b'a') => 0xA0,
+            (DeadKey::Acute, b'e') => 0x82,
+            (DeadKey::Acute, b'i') => 0xA1,
+            (DeadKey::Acute, b'o') => 0xA2,
+            (DeadKey::Acute, b'u') => 0xA3,
+            (DeadKey::Acute, b'E') => 0x90,
+            (DeadKey::Circumflex, b'a') => 0x83,
+            (DeadKey::Circumflex, b'e') => 0x88,
+            (DeadKey::Circumflex, b'i') => 0x8C,
+            (DeadKey::Circumflex, b'o') => 0x93,
+            (DeadKey::Circumflex, b'u') => 0x96,
+            (DeadKey::Diaeresis, b'a') => 0x84,
+            (DeadKey::Diaeresis, b'e') => 0x89,
+            (DeadKey::Diaeresis, b'i') => 0x8B,
+            (DeadKey::Diaeresis, b'o') => 0x94,
+            (DeadKey::Diaeresis, b'u') => 0x81,
+            (DeadKey::Diaeresis, b'y') => 0x98,
+            (DeadKey::Diaeresis, b'A') => 0x8E,
+            (DeadKey::Diaeresis, b'O') => 0x99,
+            (DeadKey::Diaeresis, b'U') => 0x9A,
+            (DeadKey::Grave, b'a') => 0x85,
+            (DeadKey::Grave, b'e') => 0x8A,
+            (DeadKey::Grave, b'i') => 0x8D,
+            (DeadKey::Grave, b'o') => 0x95,
+            (DeadKey::Grave, b'u') => 0x97,
+            (DeadKey::Ring, b'a') => 0x86,
+            (DeadKey::Ring, b'A') => 0x8F,
+            _ => c,
+        }
+    }
+}
+
+
+/// Available keyboard layouts.
+pub static LAYOUTS: [Layout; 4] = [
+    Layout {
+        name: "us",
+        description: "US QWERTY",
+        unshifted: *b"\0\x001234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ",
+        shifted: *b"\0\0!@#$%^&*()_+\0\0QWERTYUIOP{}\0\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ",
+        altgr: [0; 0x3A],
+        extra: *b"\\|\0",
+    },
+    Layout {
+        name: "sk",
+        description: "Slovak QWERTZ",
+        unshifted: *b"\0\0+lsctzy\xA0\xA1\x82=\x01\0\0qwertzuiop\xA3\x84\0\0asdfghjkl\x93\x15;\0nyxcvbnm,.-\0*\0 ",
+        shifted: *b"\0\x001234567890%\x02\0\0QWERTZUIOP/(\0\0ASDFGHJKL\"!\xF8\0)YXCVBNM?:_\0*\0 ",
+        altgr: *b"\0\0~\x02\x03\0\x06\0\x05\0\x01\0\x04\0\0\0\\|\0\0\0\0\0\0\0\0\xF6\0\0\0\0\0\0[]\0\0\0\0$\xE1\0\0\0\0#&@{}\0<>*\0\0\0\0",
+        extra: *b"&*<",
+    },
+    Layout {
+        name: "cz",
+        description: "Czech QWERTZ",
+        unshifted: *b"\0\0+escrzy\xA0\xA1\x82=\x01\0\0qwertzuiop\xA3)\0\0asdfghjklu\x15;\0\x04yxcvbnm,.-\0*\0 ",
+        shifted: *b"\0\x001234567890%\x02\0\0QWERTZUIOP/(\0\0ASDFGHJKL\"!\xF8\0'YXCVBNM?:_\0*\0 ",
+        altgr: *b"\0\0~\x02\x03\0\x06\0\x05\0\x01\0\x04\0\0\0\\|\0\0\0\0\0\0\0\0\xF6\0\0\0\0\0\0[]\0\0\0\0$\xE1\0\0\0\0#&@{}\0<>*\0\0\0\0",
+        extra: *b"\\|\0",
+    },
+    Layout {
+        name: "de",
+        description: "German QWERTZ",
+        unshifted: *b"\0\x001234567890\xE1\x01\0\0qwertzuiop\x81+\0\0asdfghjkl\x94\x84\x03\0#yxcvbnm,.-\0*\0 ",
+        shifted: *b"\0\0!\"\x15$%&/()=?\x05\0\0QWERTZUIOP\x9A*\0\0ASDFGHJKL\x99\x8E\xF8\0'YXCVBNM;:_\0*\0 ",
+        altgr: *b"\0\0\0\xFD\0\0\0\0{[]}\\\0\0\0@\0\0\0\0\0\0\0\0\0\0~\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\xE6\0\0\0\0\0\0\0",
+        extra: *b"<>|",
+    },
+];
+
+/// Index of the layout in use.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Selects layout given by `keymap=` command line option (US layout is used by default).
+pub fn init() {
+    if let Some(name) = cmdline::option("keymap")
+        && !set_layout(name)
+    {
+        vga_printf!("[boot] unknown keymap {}, using {}\n", name, current().name);
+    }
+}
+
+
+/// Returns layout in use.
+pub fn current() -> &'static Layout {
+    &LAYOUTS[CURRENT.load(Ordering::Relaxed)]
+}
+
+
+/// Switches to layout with given name. Returns false if there is no such layout.
+pub fn set_layout(name: &str) -> bool {
+    match LAYOUTS.iter().position(|layout| layout.name == name) {
+        Some(index) => {
+            CURRENT.store(index, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
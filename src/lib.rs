@@ -6,18 +6,32 @@
 extern crate alloc;
 
 
+mod acpi;
 mod allocator;
+mod apic;
 mod asyn;
+mod clock;
+mod cmdline;
 mod disk;
+mod exceptions;
+mod frame;
+mod gdt;
 mod guru;
 mod interrupts;
 mod keyboard;
+mod keymap;
 mod multiboot;
 mod paging;
 mod pic;
+mod pit;
+mod power;
 mod port;
+mod ps2;
+mod rtc;
 mod vga;
 mod shell;
+mod stack;
+mod timer;
 
 use core::panic::PanicInfo;
 
@@ -57,16 +71,18 @@ pub extern "C" fn mink_entry(multiboot_addr: usize) -> ! {
     }
     vga_printf!("[boot] retrieving boot record ...\n");
     // getting basic information using multiboot2 standard
-    let mbi = Multiboot2::from_ptr(multiboot_addr as *const u32);
+    let mbi = multiboot::boot_info().expect("Boot information not found!");
     // retrieve memory areas identified by underlying bootloader
     vga_printf!("[boot] retrieving memory map ...\n");
-    let mem_map = Multiboot2::from_ptr(multiboot_addr as *const u32)
-        .into_iter()
+    let mem_map = multiboot::boot_info()
+        .expect("Boot information not found!")
         .filter_map(|x| if let Tag::MemoryMap(m) = x { Some(m) } else { None })
         .next()
         .expect("Memory map not found!");
 
     vga_printf!("[boot] enabling interrupts ...\n");
+    // Replacing boot GDT with the kernel one, including TSS with interrupt stacks
+    gdt::init();
     // Initialising interrupt vector by loading IDT (Interrupt Descriptor Table)
     init_idt();
     // Initialising PIC8259 interrupt chain
@@ -75,9 +91,51 @@ pub extern "C" fn mink_entry(multiboot_addr: usize) -> ! {
     x86_64::instructions::interrupts::enable();
     // initialise heap memory
     vga_printf!("[boot] mapping memory ...\n");
+    frame::reserve_boot_regions(multiboot_addr);
+    frame::init(mem_map);
+    if !paging::enable_no_execute() {
+        vga_printf!("[boot] warning: no-execute protection is not supported\n");
+    }
+    vga_printf!("[boot] {} KiB of physical memory available\n", frame::free_frames() as u64 * frame::FRAME_SIZE / 1024);
+    // make all physical memory accessible at fixed offset
+    paging::map_physical_memory(mem_map);
+    // boot identity mapping is not needed anymore, lower half is left for user space
+    paging::unmap_identity_mapping();
+    // memory map was read through identity mapping, retrieve it again
+    let mem_map = multiboot::boot_info()
+        .expect("Boot information not found!")
+        .filter_map(|x| if let Tag::MemoryMap(m) = x { Some(m) } else { None })
+        .next()
+        .expect("Memory map not found!");
+    // split kernel image into 4 KiB pages with permissions of its sections
+    paging::remap_kernel(multiboot::boot_info()
+        .expect("Boot information not found!")
+        .filter_map(|x| if let Tag::ElfSymbols(s) = x { Some(s) } else { None })
+        .next()
+        .expect("Kernel ELF sections not found!"));
+    // unmap guard pages below kernel stacks
+    stack::protect_kernel_stacks();
+    // local and I/O APIC replace the legacy PIC, if present
+    if interrupts::init_apic() {
+        vga_printf!("[boot] using APIC for interrupt delivery\n");
+    } else {
+        vga_printf!("[boot] APIC not found, using 8259 PIC\n");
+    }
+    // select clock source before the timer starts counting
+    clock::init();
+    timer::init();
+    vga_printf!("[boot] using {} clock source ({} Hz)\n", clock::source().name(), clock::frequency());
+    rtc::init();
     let mut mapper = paging::get_page_mapper(None);
-    let mut frame_alloc = allocator::NormalFrameAllocator::new(&mem_map);
+    let mut frame_alloc = frame::KernelFrameAllocator;
     allocator::heap_init(&mut mapper, &mut frame_alloc).expect("heap memory init failed!");
+    // configure PS/2 controller and keyboard before its IRQ is enabled
+    if !ps2::init() {
+        vga_printf!("[boot] PS/2 controller not found\n");
+    }
+    // keyboard scancode queue is allocated on the heap
+    keyboard::init();
+    keymap::init();
 
     vga_printf!("[boot] initialising disk interface ...\n");
     // Initiase ATA PIO driver
@@ -95,8 +153,8 @@ pub extern "C" fn mink_entry(multiboot_addr: usize) -> ! {
     vga_printf!("\nMBI LOADED WITH : SIZE {}, RESERVED {}\n", mbi.total_size, mbi.reserved);
 
     // maybe for later use: retrieve kernel base address
-    let _load_addr = Multiboot2::from_ptr(multiboot_addr as *const u32)
-        .into_iter()
+    let _load_addr = multiboot::boot_info()
+        .expect("Boot information not found!")
         .filter_map(|x| if let Tag::ImgLoadBaseAddr(k) = x { Some(k) } else { None })
         .next()
         .expect("Kernel base address not found!");
@@ -132,13 +190,14 @@ pub extern "C" fn mink_entry(multiboot_addr: usize) -> ! {
 /// Asynchronous task taking care of user shell operation.
 async fn start_shell() {
     let mut shell = shell::Shell::new();
-    shell.run();
+    shell.run().await;
 }
 
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
     vga::vga_clear_screen();
     vga_printf!("RECEIVED PANIC SIGNAL : {:?}", info);
+    allocator::report_out_of_memory();
     loop {}
     // guru::guru_panic(&info)
 }
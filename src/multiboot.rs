@@ -6,8 +6,19 @@ const fn addr_align(addr: usize, alignment: usize) -> usize {
     ((addr + alignment - 1) & !(alignment - 1))
 }
 
+/// Physical address of multiboot boot information structure.
 pub static mut MULTIBOOT_INFO_ADDR: usize = 0;
 
+
+/// Returns boot information structure, accessed through the physical memory mapping.
+pub fn boot_info() -> Option<Multiboot2> {
+    let addr = unsafe { MULTIBOOT_INFO_ADDR };
+    if addr == 0 {
+        return None;
+    }
+    Some(Multiboot2::from_ptr(crate::paging::phys_to_virt(addr as u64).as_ptr()))
+}
+
 #[repr(u32)]
 #[derive(Debug, Clone, Copy)]
 pub enum TagType {
@@ -126,6 +137,13 @@ pub enum ElfSectionType {
     HiUser = 0xffffffff,
 }
 
+/// Section contains data writable during execution (SHF_WRITE).
+pub const ELF_SECTION_WRITE: u64 = 0x1;
+/// Section occupies memory during execution (SHF_ALLOC).
+pub const ELF_SECTION_ALLOC: u64 = 0x2;
+/// Section contains executable machine instructions (SHF_EXECINSTR).
+pub const ELF_SECTION_EXECINSTR: u64 = 0x4;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 /// defined according to Portable Formats Specification, Version 1.1 (1-9)
@@ -184,7 +202,7 @@ pub struct ElfSection {
     pub entry_size: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ElfSymbols {
     len: u32,
     entry_size: u32,
@@ -247,6 +265,48 @@ pub struct AcpiNewRspd {
     reserved: [u8; 3],
 }
 
+impl AcpiOldRspd {
+    /// Returns physical address of Root System Description Table.
+    pub fn rsdt_addr(&self) -> u32 {
+        self.addr
+    }
+
+    /// Returns ACPI revision (0 for ACPI 1.0, 2 for ACPI 2.0 and later).
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// Returns OEM identification string.
+    pub fn oem_id(&self) -> [u8; 6] {
+        self.oem_id
+    }
+
+    /// Returns whether the structure has the "RSD PTR " signature.
+    pub fn has_valid_signature(&self) -> bool {
+        &self.signature == b"RSD PTR "
+    }
+}
+
+impl AcpiNewRspd {
+    /// Returns ACPI 1.0 part of the structure.
+    pub fn old(&self) -> &AcpiOldRspd {
+        &self.old
+    }
+
+    /// Returns physical address of Extended System Description Table.
+    pub fn xsdt_addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Size of ACPI 2.0 RSDP structure without padding.
+    pub const SIZE: usize = 36;
+
+    /// Returns length of the whole structure (covered by extended checksum).
+    pub fn length(&self) -> u32 {
+        self.len
+    }
+}
+
 #[derive(Debug)]
 pub enum Tag {
     /// string representing the command line
@@ -373,7 +433,11 @@ pub enum Tag {
     AcpiOldRspd(&'static AcpiOldRspd),
 
     /// https://wiki.osdev.org/RSDP
-    AcpiNewRspd(&'static AcpiNewRspd),
+    AcpiNewRspd {
+        rsdp: &'static AcpiNewRspd,
+        /// Raw bytes of the structure, as long as the tag is.
+        data: &'static [u8],
+    },
 
     /// This tag indicates ExitBootServices wasn’t called
     EfiBootNotTerminated,
@@ -466,9 +530,11 @@ impl Tag {
                 TagType::AcpiOldRspd => {
                     Self::AcpiOldRspd(&*(ptr as *const AcpiOldRspd))
                 }
-                TagType::AcpiNewRspd => {
-                    Self::AcpiNewRspd(&*(ptr as *const AcpiNewRspd))
-                }
+                TagType::AcpiNewRspd => Self::AcpiNewRspd {
+                    rsdp: &*(ptr as *const AcpiNewRspd),
+                    // 8 is size of the tag_info
+                    data: slice::from_raw_parts(ptr.cast(), tag_info.size as usize - 8),
+                },
                 // TODO: TagType::NetInfo
                 // TODO: TagType::EfiMemoryMap
                 TagType::EfiBootNotTerminated => Self::EfiBootNotTerminated,
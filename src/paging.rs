@@ -1,23 +1,312 @@
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use x86_64::structures::paging::PageTable;
+use x86_64::structures::paging as Paging;
+use x86_64::structures::paging::{Mapper, PageTable};
 use x86_64::registers::control::Cr3;
 
+use crate::{frame, multiboot::{self, ElfSection, MemoryMapEntry, MemoryMapType}, vga_printf};
+
+
+/// Virtual address, at which the whole physical memory is mapped.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+/// Virtual address, at which the kernel image is linked (see `linker/link.ld`). First GiB of
+/// physical memory is mapped here by boot code.
+pub const KERNEL_OFFSET: u64 = 0xFFFF_FFFF_8000_0000;
+
+/// Offset currently used to access physical memory. Zero (identity mapping of the first GiB set
+/// up by boot code) until `map_physical_memory` switches it to `PHYSICAL_MEMORY_OFFSET`.
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 
 /// Returns new page mapper structure, which can be used to retrieve information about pages or
-/// mapping new pages to memory.
+/// mapping new pages to memory. Current physical memory offset is used if none is given.
 #[allow(unused)]
 pub fn get_page_mapper(phys_offset: Option<x86_64::VirtAddr>) -> Paging::OffsetPageTable<'static> {
     let (cr3, _) = Cr3::read();
-    unsafe { 
+    let phys_offset = phys_offset.unwrap_or(self::phys_offset());
+    unsafe {
         Paging::OffsetPageTable::new(
-            &mut *(cr3.start_address().as_u64() as *mut PageTable),
-            phys_offset.unwrap_or(x86_64::VirtAddr::zero()))
+            &mut *(phys_offset + cr3.start_address().as_u64()).as_mut_ptr::<PageTable>(),
+            phys_offset)
+    }
+}
+
+
+unsafe extern "C" {
+    // defined in linker/link.ld
+    static _kernel_start: u8;
+    static _kernel_end: u8;
+}
+
+
+/// Returns offset of virtual memory region mapping all physical memory.
+pub fn phys_offset() -> x86_64::VirtAddr {
+    x86_64::VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed))
+}
+
+
+/// Translates physical address into virtual address, through which it can be accessed.
+pub fn phys_to_virt(addr: u64) -> x86_64::VirtAddr {
+    phys_offset() + addr
+}
+
+
+/// Returns how many bytes (at most `len`) starting at virtual address `addr` are mapped.
+pub fn mapped_length(addr: x86_64::VirtAddr, len: u64) -> u64 {
+    use Paging::mapper::{Translate, TranslateResult};
+    let mapper = get_page_mapper(None);
+    let mut mapped = 0;
+    while mapped < len {
+        match mapper.translate(addr + mapped) {
+            TranslateResult::Mapped { frame, offset, .. } => mapped += frame.size() - offset,
+            _ => break,
+        }
+    }
+    mapped.min(len)
+}
+
+
+/// Translates address inside the kernel image into physical address. Addresses of the low boot
+/// code (linked at physical addresses) are returned unchanged.
+pub fn kernel_virt_to_phys(addr: u64) -> u64 {
+    if addr >= KERNEL_OFFSET {
+        addr - KERNEL_OFFSET
+    } else {
+        addr
+    }
+}
+
+
+/// Removes identity mapping of the first GiB created by boot code, leaving the lower half of
+/// address space free. Physical memory has to be accessed through `phys_to_virt` afterwards.
+pub fn unmap_identity_mapping() {
+    let mut mapper = get_page_mapper(None);
+    mapper.level_4_table_mut()[0].set_unused();
+    x86_64::instructions::tlb::flush_all();
+}
+
+
+/// Enables no-execute page protection (EFER.NXE), if the CPU supports it. Returns whether
+/// the protection is enabled.
+pub fn enable_no_execute() -> bool {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    // CPUID extended function 0x80000001, EDX bit 20 (NX)
+    if core::arch::x86_64::__cpuid(0x80000001).edx & (1 << 20) == 0 {
+        return false;
+    }
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    true
+}
+
+
+/// Returns `NO_EXECUTE` page flag if no-execute protection is enabled, empty flags otherwise
+/// (setting the bit with protection disabled causes page faults).
+pub fn no_execute_flag() -> Paging::PageTableFlags {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        Paging::PageTableFlags::NO_EXECUTE
+    } else {
+        Paging::PageTableFlags::empty()
+    }
+}
+
+
+/// Returns page table stored in given physical frame for modification.
+fn table_at_mut(frame: Paging::PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address().as_u64()).as_mut_ptr::<PageTable>() }
+}
+
+
+/// Returns flags of the kernel image page at `virt` - union of permissions of all allocated
+/// sections sharing the page, read-only and non-executable if there is none.
+fn image_page_flags(sections: impl Iterator<Item = &'static ElfSection>, virt: u64) -> Paging::PageTableFlags {
+    use Paging::PageTableFlags as F;
+    let mut flags = F::PRESENT | F::GLOBAL | no_execute_flag();
+    for section in sections {
+        if section.flags & multiboot::ELF_SECTION_ALLOC == 0 || section.size == 0
+            || section.addr >= virt + frame::FRAME_SIZE || section.addr + section.size <= virt {
+            continue;
+        }
+        if section.flags & multiboot::ELF_SECTION_WRITE != 0 {
+            flags |= F::WRITABLE;
+        }
+        if section.flags & multiboot::ELF_SECTION_EXECINSTR != 0 {
+            flags.remove(F::NO_EXECUTE);
+        }
+    }
+    flags
+}
+
+
+/// Remaps kernel image with 4 KiB pages according to flags of its ELF sections - code is mapped
+/// read-only executable, read-only data as read-only non-executable and writable data as
+/// writable non-executable. Rest of the first GiB mapped at `KERNEL_OFFSET` by boot code is
+/// unmapped. Alias of the image in the physical memory map is split into 4 KiB pages as well
+/// and is only writable where the image is. Write protection is then enforced for the kernel
+/// as well (CR0.WP).
+///
+/// Must be called after the identity mapping is removed, since the boot page directory is
+/// shared by both mappings, and after physical memory is mapped.
+pub fn remap_kernel(sections: impl Iterator<Item = &'static ElfSection> + Clone) {
+    use Paging::PageTableFlags as F;
+    const HUGE_PAGE_SIZE: u64 = 0x200000;
+
+    let image_start = (&raw const _kernel_start as u64) & !(frame::FRAME_SIZE - 1);
+    let image_end = (&raw const _kernel_end as u64).next_multiple_of(frame::FRAME_SIZE);
+
+    // boot code maps P4[511] -> P3[510] -> page directory of 2 MiB pages
+    let mapper = get_page_mapper(None);
+    let kernel_page = Paging::Page::<Paging::Size4KiB>::containing_address(x86_64::VirtAddr::new(KERNEL_OFFSET));
+    let p3_frame = mapper.level_4_table()[kernel_page.p4_index()].frame()
+        .expect("kernel is not mapped!");
+    let p2_frame = table_at_mut(p3_frame)[kernel_page.p3_index()].frame()
+        .expect("kernel is not mapped by 2 MiB pages!");
+    let p2 = table_at_mut(p2_frame);
+
+    for (i, entry) in p2.iter_mut().enumerate() {
+        let virt = KERNEL_OFFSET + i as u64 * HUGE_PAGE_SIZE;
+        if virt + HUGE_PAGE_SIZE <= image_start || virt >= image_end {
+            entry.set_unused();
+            continue;
+        }
+        // the huge page may map code or stack in use right now, so its replacement has to be
+        // complete before it is put in place
+        let p1_frame = frame::allocate_frames(1).expect("out of memory while remapping kernel!");
+        let p1 = table_at_mut(p1_frame);
+        p1.zero();
+        for (j, page) in p1.iter_mut().enumerate() {
+            let page_virt = virt + j as u64 * frame::FRAME_SIZE;
+            if page_virt >= image_start && page_virt < image_end {
+                let flags = image_page_flags(sections.clone(), page_virt);
+                page.set_addr(x86_64::PhysAddr::new(page_virt - KERNEL_OFFSET), flags);
+            }
+        }
+        entry.set_frame(p1_frame, F::PRESENT | F::WRITABLE);
+        for page_virt in (virt..virt + HUGE_PAGE_SIZE).step_by(frame::FRAME_SIZE as usize) {
+            x86_64::instructions::tlb::flush(x86_64::VirtAddr::new(page_virt));
+        }
+    }
+
+    // physical memory map must not allow writes to the image, which the kernel mapping forbids
+    for virt in (image_start..image_end).step_by(frame::FRAME_SIZE as usize) {
+        let page = Paging::Page::<Paging::Size4KiB>::containing_address(x86_64::VirtAddr::new(virt));
+        let p1_frame = p2[page.p2_index()].frame().expect("kernel image page is not mapped!");
+        let writable = table_at_mut(p1_frame)[page.p1_index()].flags() & F::WRITABLE;
+        let alias = physical_map_entry(virt - KERNEL_OFFSET);
+        alias.set_flags((alias.flags() - F::WRITABLE) | writable);
+    }
+    x86_64::instructions::tlb::flush_all();
+
+    unsafe {
+        x86_64::registers::control::Cr0::update(|flags| {
+            flags.insert(x86_64::registers::control::Cr0Flags::WRITE_PROTECT)
+        });
     }
 }
 
-use x86_64::structures::paging as Paging;
 
-use crate::vga_printf;
+/// Replaces huge page mapped by given entry with a page table of 512 smaller pages with same
+/// flags, so they can be changed individually. `page_size` is size of the huge page.
+fn split_huge_page(entry: &mut Paging::page_table::PageTableEntry, page_size: u64) {
+    use Paging::PageTableFlags as F;
+    let flags = entry.flags();
+    if !flags.contains(F::HUGE_PAGE) {
+        return;
+    }
+    let base = entry.addr().as_u64();
+    let child_size = page_size / 512;
+    // 4 KiB pages use the huge page bit as PAT bit
+    let child_flags = if child_size == frame::FRAME_SIZE { flags - F::HUGE_PAGE } else { flags };
+    let table_frame = frame::allocate_frames(1).expect("out of memory while splitting huge page!");
+    let table = table_at_mut(table_frame);
+    for (i, child) in table.iter_mut().enumerate() {
+        child.set_addr(x86_64::PhysAddr::new(base + i as u64 * child_size), child_flags);
+    }
+    entry.set_frame(table_frame, F::PRESENT | F::WRITABLE);
+}
+
+
+/// Returns 4 KiB page table entry mapping given physical address in the physical memory map,
+/// splitting huge pages covering it.
+fn physical_map_entry(phys: u64) -> &'static mut Paging::page_table::PageTableEntry {
+    const GIB: u64 = 1 << 30;
+    let page = Paging::Page::<Paging::Size4KiB>::containing_address(x86_64::VirtAddr::new(PHYSICAL_MEMORY_OFFSET + phys));
+    let p4 = table_at_mut(Cr3::read().0);
+    let p3 = table_at_mut(p4[page.p4_index()].frame().expect("physical memory is not mapped!"));
+    split_huge_page(&mut p3[page.p3_index()], GIB);
+    let p2 = table_at_mut(p3[page.p3_index()].frame().expect("physical memory is not mapped!"));
+    split_huge_page(&mut p2[page.p2_index()], 2 << 20);
+    let p1 = table_at_mut(p2[page.p2_index()].frame().expect("physical memory is not mapped!"));
+    &mut p1[page.p1_index()]
+}
+
+
+/// Returns whether the CPU supports 1 GiB pages.
+fn has_gigabyte_pages() -> bool {
+    // CPUID extended function 0x80000001, EDX bit 26 (Page1GB)
+    core::arch::x86_64::__cpuid(0x80000001).edx & (1 << 26) != 0
+}
+
+
+/// Frame allocator for page tables of the physical memory map - they are written through the
+/// boot identity mapping, which only covers the first GiB.
+struct LowFrameAllocator;
+
+unsafe impl Paging::FrameAllocator<Paging::Size4KiB> for LowFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Paging::PhysFrame> {
+        frame::allocate_frame_below(1 << 30)
+    }
+}
+
+
+/// Maps whole 32-bit address space (where memory mapped devices live) and all RAM above it
+/// reported by the bootloader at `PHYSICAL_MEMORY_OFFSET`, using largest pages the CPU supports.
+/// From now on, physical memory is accessed through this region.
+///
+/// Must be called while the boot identity mapping is still present, page tables created here
+/// are allocated from the first GiB.
+pub fn map_physical_memory(mem_map: &[MemoryMapEntry]) {
+    const LOW_WINDOW: u64 = 4 << 30;
+    // ACPI tables may be placed in RAM above 4 GiB as well
+    let high_ram = mem_map
+        .iter()
+        .filter(|m| matches!(m.typ, MemoryMapType::Available | MemoryMapType::AcpiInfo))
+        .filter(|m| m.base_addr + m.length > LOW_WINDOW)
+        .map(|m| (m.base_addr.max(LOW_WINDOW), m.base_addr + m.length));
+
+    let mut mapper = get_page_mapper(None);
+    for (start, end) in core::iter::once((0, LOW_WINDOW)).chain(high_ram) {
+        if has_gigabyte_pages() {
+            map_physical_range::<Paging::Size1GiB>(&mut mapper, start, end);
+        } else {
+            map_physical_range::<Paging::Size2MiB>(&mut mapper, start, end);
+        }
+    }
+    PHYS_OFFSET.store(PHYSICAL_MEMORY_OFFSET, Ordering::Relaxed);
+}
+
+
+/// Maps physical memory `[start, end)` at `PHYSICAL_MEMORY_OFFSET` using pages of size `S`.
+fn map_physical_range<S: Paging::PageSize + core::fmt::Debug>(mapper: &mut Paging::OffsetPageTable, start: u64, end: u64)
+where
+    for<'a> Paging::OffsetPageTable<'a>: Paging::Mapper<S>,
+{
+    let flags = Paging::PageTableFlags::PRESENT
+        | Paging::PageTableFlags::WRITABLE
+        | Paging::PageTableFlags::GLOBAL
+        | no_execute_flag();
+    let start = start & !(S::SIZE - 1);
+    for addr in (start..end.next_multiple_of(S::SIZE)).step_by(S::SIZE as usize) {
+        let page = Paging::Page::<S>::containing_address(x86_64::VirtAddr::new(PHYSICAL_MEMORY_OFFSET + addr));
+        let frame = Paging::PhysFrame::<S>::containing_address(x86_64::PhysAddr::new(addr));
+        match unsafe { mapper.map_to(page, frame, flags, &mut LowFrameAllocator) } {
+            Ok(f) => f.flush(),
+            // neighbouring memory map entries may share a page
+            Err(Paging::mapper::MapToError::PageAlreadyMapped(_)) => {}
+            Err(e) => panic!("failed to map physical memory at {:#x}: {:?}", addr, e),
+        }
+    }
+}
 
 
 /// Map given huge page to given physical memory frame using given mapper with given flags.
@@ -35,68 +324,170 @@ pub fn map_huge_page(
     let page_map = mapper.level_4_table_mut();
     let page_map_entry = &mut page_map[page.p4_index()];
     // Retrieve PDP (level 3)
-    let page_dir_pointer = unsafe { &mut *(page_map_entry
+    let page_dir_pointer = unsafe { &mut *phys_to_virt(page_map_entry
         .frame()
         .expect("Failed to dereference page map entry!")
         .start_address()
-        .as_u64()
-        as *mut PageTable)
+        .as_u64())
+        .as_mut_ptr::<PageTable>()
     };
     let pdp_entry = &mut page_dir_pointer[page.p3_index()];
     // Retrieve PD (level 2)
     let page_dir = unsafe {
-        &mut *(pdp_entry
+        &mut *phys_to_virt(pdp_entry
             .frame()
             .expect("Failed to dereference page directory pointer!")
             .start_address()
-            .as_u64() as *mut PageTable)
+            .as_u64())
+            .as_mut_ptr::<PageTable>()
     };
     let pd_entry = &mut page_dir[page.p2_index()];
     pd_entry.set_addr(memory_frame.start_address(), flags);
 }
 
 
-/// BROKEN: Debug prints page mappings currently in use.
-/// BROKEN: This function does not check poiner validity - null pointer panics
-#[allow(unused)]
-pub fn debug_print_huge_pages(mapper: &mut Paging::OffsetPageTable) {
-    let mut counter = 0usize;
-    // Retrieve page map (level 4)
-    let page_map = mapper.level_4_table_mut();
-    for x in page_map.iter() {
-        if !x.flags().contains(Paging::PageTableFlags::PRESENT) || x.is_unused() { continue; }
-        vga_printf!("Page Map Entry : {:?}\n", x.addr());
-        // Retrieve PDP (level 3)
-        let page_dir_pointer = unsafe { &mut *(x
-            .frame()
-            .expect("Failed to dereference page map entry!")
-            .start_address()
-            .as_u64()
-            as *mut PageTable)
-        };
-        for y in page_dir_pointer.iter() {
-            if !y.flags().contains(Paging::PageTableFlags::PRESENT) || y.is_unused() { continue; }
-            vga_printf!("Page Directory Pointer Entry : {:?}\n", y.addr());
-            // Retrieve PD (level 2)
-            let page_dir = unsafe {
-                &mut *(y
-                    .frame()
-                    .expect("Failed to dereference page directory pointer!")
-                    .start_address()
-                    .as_u64() as *mut PageTable)
-            };
-            let flags = Paging::PageTableFlags::PRESENT | Paging::PageTableFlags::HUGE_PAGE;
-            let entries = page_dir
-                .iter()
-                .filter(|x| x.flags().contains(flags));
-
-            for z in entries.take(10) {
-                if !z.flags().contains(flags) || z.is_unused() { continue; }
-                vga_printf!("Page Directory Entry : {:?}\n", z.addr());
+/// Contiguous range of virtual memory mapped onto contiguous physical memory with the same
+/// effective flags and page size.
+#[derive(Clone, Copy, Debug)]
+pub struct MappedRange {
+    pub virt_start: u64,
+    pub phys_start: u64,
+    /// Size of the whole range in bytes.
+    pub size: u64,
+    /// Size of the pages the range consists of (4 KiB, 2 MiB or 1 GiB).
+    pub page_size: u64,
+    /// Effective flags - WRITABLE and USER_ACCESSIBLE only if set on every level, NO_EXECUTE if
+    /// set on any level, GLOBAL from the last level entry.
+    pub flags: Paging::PageTableFlags,
+}
+
+
+impl MappedRange {
+    /// Returns short permission string in form `rwxug`, dash for missing permission.
+    pub fn permissions(&self) -> [u8; 5] {
+        let f = self.flags;
+        [
+            b'r',
+            if f.contains(Paging::PageTableFlags::WRITABLE) { b'w' } else { b'-' },
+            if f.contains(Paging::PageTableFlags::NO_EXECUTE) { b'-' } else { b'x' },
+            if f.contains(Paging::PageTableFlags::USER_ACCESSIBLE) { b'u' } else { b'-' },
+            if f.contains(Paging::PageTableFlags::GLOBAL) { b'g' } else { b'-' },
+        ]
+    }
+
+    /// Tries to extend this range by directly following range, returns false if they differ.
+    fn merge(&mut self, next: &MappedRange) -> bool {
+        let contiguous = self.virt_start + self.size == next.virt_start
+            && self.phys_start + self.size == next.phys_start;
+        if contiguous && self.flags == next.flags && self.page_size == next.page_size {
+            self.size += next.size;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+
+/// Combines flags of parent table entry with flags of its child entry.
+fn effective_flags(parent: Paging::PageTableFlags, entry: Paging::PageTableFlags) -> Paging::PageTableFlags {
+    use Paging::PageTableFlags as F;
+    let inherited = F::WRITABLE | F::USER_ACCESSIBLE;
+    (parent & entry & inherited)
+        | ((parent | entry) & F::NO_EXECUTE)
+        | (entry & F::GLOBAL)
+}
+
+
+/// Returns page table stored in given physical frame, accessed through mapper's physical offset.
+fn table_at(mapper: &Paging::OffsetPageTable, frame: Paging::PhysFrame) -> &'static PageTable {
+    let virt = mapper.phys_offset() + frame.start_address().as_u64();
+    unsafe { &*virt.as_ptr::<PageTable>() }
+}
+
+
+/// Walks all page tables reachable from mapper's level 4 table and calls `f` for every
+/// contiguous mapped range, in ascending virtual address order.
+pub fn walk_mappings(mapper: &Paging::OffsetPageTable, mut f: impl FnMut(&MappedRange)) {
+    use Paging::PageTableFlags as F;
+    const GIB: u64 = 1 << 30;
+    const MIB2: u64 = 1 << 21;
+    const KIB4: u64 = 1 << 12;
+
+    let mut current: Option<MappedRange> = None;
+    let mut emit = |range: MappedRange| {
+        if let Some(c) = current.as_mut() {
+            if c.merge(&range) {
+                return;
+            }
+            f(c);
+        }
+        current = Some(range);
+    };
+
+    // top flags: everything is allowed until some level restricts it
+    let root_flags = F::WRITABLE | F::USER_ACCESSIBLE;
+    for (i4, e4) in mapper.level_4_table().iter().enumerate() {
+        if !e4.flags().contains(F::PRESENT) {
+            continue;
+        }
+        // sign extend bit 47 to get canonical address
+        let base4 = (((i4 as u64) << 39) << 16) as i64 >> 16;
+        let flags4 = effective_flags(root_flags, e4.flags());
+        for (i3, e3) in table_at(mapper, e4.frame().unwrap()).iter().enumerate() {
+            if !e3.flags().contains(F::PRESENT) {
+                continue;
+            }
+            let base3 = base4 as u64 + i3 as u64 * GIB;
+            let flags3 = effective_flags(flags4, e3.flags());
+            if e3.flags().contains(F::HUGE_PAGE) {
+                emit(MappedRange { virt_start: base3, phys_start: e3.addr().as_u64(), size: GIB, page_size: GIB, flags: flags3 });
+                continue;
+            }
+            for (i2, e2) in table_at(mapper, e3.frame().unwrap()).iter().enumerate() {
+                if !e2.flags().contains(F::PRESENT) {
+                    continue;
+                }
+                let base2 = base3 + i2 as u64 * MIB2;
+                let flags2 = effective_flags(flags3, e2.flags());
+                if e2.flags().contains(F::HUGE_PAGE) {
+                    emit(MappedRange { virt_start: base2, phys_start: e2.addr().as_u64(), size: MIB2, page_size: MIB2, flags: flags2 });
+                    continue;
+                }
+                for (i1, e1) in table_at(mapper, e2.frame().unwrap()).iter().enumerate() {
+                    if !e1.flags().contains(F::PRESENT) {
+                        continue;
+                    }
+                    let base1 = base2 + i1 as u64 * KIB4;
+                    let flags1 = effective_flags(flags2, e1.flags());
+                    emit(MappedRange { virt_start: base1, phys_start: e1.addr().as_u64(), size: KIB4, page_size: KIB4, flags: flags1 });
+                }
             }
-            counter += page_dir.iter().filter(|x| x.flags().contains(flags)).count();
         }
     }
-    vga_printf!("TOTAL NUMBER OF PAGES : {}", counter);
+    if let Some(c) = current {
+        f(&c);
+    }
 }
 
+
+/// Prints all mapped ranges of given page mapper.
+#[allow(unused)]
+pub fn print_mappings(mapper: &Paging::OffsetPageTable) {
+    let mut total = 0u64;
+    walk_mappings(mapper, |range| {
+        let perms = range.permissions();
+        let page = match range.page_size {
+            0x1000 => "4K",
+            0x200000 => "2M",
+            _ => "1G",
+        };
+        vga_printf!(
+            "{:016x}-{:016x} -> {:010x} {} {} {} KiB\n",
+            range.virt_start, range.virt_start + range.size, range.phys_start,
+            core::str::from_utf8(&perms).unwrap_or("?????"), page, range.size / 1024
+        );
+        total += range.size;
+    });
+    vga_printf!("total mapped : {} KiB\n", total / 1024);
+}
@@ -1,13 +1,24 @@
 use pic8259::ChainedPics;
 
+use crate::port::{input_byte, output_byte};
+
 
 const PIC1_OFFSET: u8 = 32;
 const PIC2_OFFSET: u8 = 40;
 
+/// Number of interrupt lines of both chained PICs.
+pub const IRQ_LINES: usize = 16;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC2_COMMAND: u16 = 0xA0;
+/// OCW3 command to read In-Service Register on next read of command port.
+const PIC_READ_ISR: u8 = 0x0B;
+const PIC_EOI: u8 = 0x20;
+
 static CHAINED_PICS: spin::Mutex<pic8259::ChainedPics> = spin::Mutex::new(unsafe {ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET)});
 
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum IRQ {
     Timer = PIC1_OFFSET,
     Keyboard,
@@ -27,10 +38,99 @@ pub enum IRQ {
     SecondaryATA,
 }
 
+impl IRQ {
+    /// All IRQs, ordered by their line number.
+    pub const ALL: [IRQ; IRQ_LINES] = [
+        IRQ::Timer, IRQ::Keyboard, IRQ::Cascade, IRQ::COM2,
+        IRQ::COM1, IRQ::LPT2, IRQ::FloppyDisk, IRQ::LPT1,
+        IRQ::CMOSClock, IRQ::Nic1, IRQ::Nic2, IRQ::Nic3,
+        IRQ::Mouse, IRQ::Coprocessor, IRQ::PrimaryATA, IRQ::SecondaryATA,
+    ];
+
+    /// Returns PIC input line of the IRQ (0-15).
+    pub fn line(self) -> u8 {
+        self as u8 - PIC1_OFFSET
+    }
+
+    /// Returns interrupt vector the IRQ is delivered to.
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Initialises both PICs with all lines masked, except the cascade line. Lines are unmasked
+/// once a handler is registered for them.
 pub fn init() {
-    unsafe {
-        CHAINED_PICS.lock().initialize();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = CHAINED_PICS.lock();
+        unsafe {
+            pics.initialize();
+            pics.write_masks(!(1 << IRQ::Cascade.line()), 0xFF);
+        }
+    });
+}
+
+/// Masks all lines of both PICs, so they do not deliver any interrupts anymore.
+pub fn disable() {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        CHAINED_PICS.lock().disable();
+    });
+}
+
+/// Enables delivery of given IRQ.
+pub fn unmask(irq: IRQ) {
+    set_masked(irq, false);
+}
+
+/// The lock is also taken from interrupt context (see `end_of_interrupt`), so it is only held
+/// with interrupts disabled.
+fn set_masked(irq: IRQ, masked: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = CHAINED_PICS.lock();
+        unsafe {
+            let mut masks = pics.read_masks();
+            let (pic, bit) = ((irq.line() / 8) as usize, irq.line() % 8);
+            if masked {
+                masks[pic] |= 1 << bit;
+            } else {
+                masks[pic] &= !(1 << bit);
+            }
+            pics.write_masks(masks[0], masks[1]);
+        }
+    });
+}
+
+/// Returns whether given IRQ is masked.
+#[allow(unused)]
+pub fn is_masked(irq: IRQ) -> bool {
+    let masks = x86_64::instructions::interrupts::without_interrupts(|| unsafe { CHAINED_PICS.lock().read_masks() });
+    masks[(irq.line() / 8) as usize] & (1 << (irq.line() % 8)) != 0
+}
+
+/// Returns In-Service Register of both PICs (master in the low byte).
+fn read_isr() -> u16 {
+    let _pics = CHAINED_PICS.lock();
+    output_byte(PIC1_COMMAND, PIC_READ_ISR);
+    output_byte(PIC2_COMMAND, PIC_READ_ISR);
+    (input_byte(PIC2_COMMAND) as u16) << 8 | input_byte(PIC1_COMMAND) as u16
+}
+
+/// Checks whether given IRQ is spurious - IRQ 7 and 15 are raised by PIC when the real
+/// interrupt request disappears before it is acknowledged, in which case the line is not
+/// marked in In-Service Register. Spurious IRQ must not be acknowledged, although spurious
+/// IRQ 15 still needs end of interrupt sent to the master PIC (it did see cascade line).
+pub fn is_spurious(irq: IRQ) -> bool {
+    if irq != IRQ::LPT1 && irq != IRQ::SecondaryATA {
+        return false;
+    }
+    if read_isr() & (1 << irq.line()) != 0 {
+        return false;
+    }
+    if irq == IRQ::SecondaryATA {
+        let _pics = CHAINED_PICS.lock();
+        output_byte(PIC1_COMMAND, PIC_EOI);
     }
+    true
 }
 
 pub fn end_of_interrupt(irq: IRQ) {
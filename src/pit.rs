@@ -0,0 +1,72 @@
+use crate::port::{input_byte, output_byte};
+
+/// Input clock frequency of the PIT (in Hz).
+pub const PIT_FREQUENCY: u32 = 1193182;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Port controlling channel 2 gate (bit 0) and speaker (bit 1), bit 5 reflects channel 2 output.
+const CHANNEL2_GATE: u16 = 0x61;
+
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator).
+const CHANNEL0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// Longest interval channel 2 can measure at once (in us).
+const MAX_ONE_SHOT_US: u64 = 50_000;
+
+/// Serialises access to PIT ports, since programming a channel takes multiple writes.
+static PIT_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+
+/// Programs channel 0 to generate IRQ 0 with given frequency. Returns actual period between
+/// interrupts in ns (frequency is rounded to the nearest possible divisor).
+pub fn set_frequency(frequency: u32) -> u64 {
+    let divisor = ((PIT_FREQUENCY + frequency / 2) / frequency.max(1)).clamp(2, 65535);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _lock = PIT_LOCK.lock();
+        output_byte(COMMAND, CHANNEL0_RATE_GENERATOR);
+        output_byte(CHANNEL0_DATA, divisor as u8);
+        output_byte(CHANNEL0_DATA, (divisor >> 8) as u8);
+    });
+    divisor as u64 * 1_000_000_000 / PIT_FREQUENCY as u64
+}
+
+
+/// Counts down given number of PIT clock cycles on channel 2, which does not generate any
+/// interrupt, and waits until the countdown finishes. `started` is called right after the
+/// countdown is started. Channel 2 stays locked with interrupts disabled the whole time, so
+/// countdowns of different users cannot restart each other.
+pub fn one_shot(count: u16, started: impl FnOnce()) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _lock = PIT_LOCK.lock();
+        // enable channel 2 gate, disable speaker output
+        output_byte(CHANNEL2_GATE, (input_byte(CHANNEL2_GATE) & !0x02) | 0x01);
+        output_byte(COMMAND, CHANNEL2_ONE_SHOT);
+        output_byte(CHANNEL2_DATA, count as u8);
+        output_byte(CHANNEL2_DATA, (count >> 8) as u8);
+        // restart countdown by toggling the gate
+        let gate = input_byte(CHANNEL2_GATE);
+        output_byte(CHANNEL2_GATE, gate & !0x01);
+        output_byte(CHANNEL2_GATE, gate | 0x01);
+        started();
+        // channel 2 output goes high once the countdown finishes
+        while input_byte(CHANNEL2_GATE) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+
+/// Waits given number of microseconds by polling PIT channel 2. Works with interrupts disabled.
+pub fn busy_wait_us(us: u64) {
+    let mut remaining = us;
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_ONE_SHOT_US);
+        let count = (PIT_FREQUENCY as u64 * chunk / 1_000_000).max(1);
+        one_shot(count as u16, || {});
+        remaining -= chunk;
+    }
+}
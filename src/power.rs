@@ -0,0 +1,184 @@
+use crate::acpi::{self, AddressSpace, Fadt, GenericAddress};
+use crate::paging;
+use crate::pit;
+use crate::port::{input_word, output_byte, output_word};
+use crate::ps2;
+
+/// SCI_EN bit of PM1 control register, set when the system is in ACPI mode.
+const PM1_SCI_EN: u16 = 1 << 0;
+/// SLP_EN bit of PM1 control register, entering the sleep state written in SLP_TYP.
+const PM1_SLP_EN: u16 = 1 << 13;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+
+// AML opcodes used in `_S5_` object definition
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+
+/// Time given to firmware to switch into ACPI mode (in ms).
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 1000;
+/// Time given to a reset method to take effect before trying the next one (in ms).
+const RESET_TIMEOUT_MS: u64 = 500;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+
+/// Finds `_S5_` (soft off) object in DSDT and returns its SLP_TYPa and SLP_TYPb values.
+///
+/// Full AML interpreter is not available, so the object is expected in its usual simple form:
+/// `Name(_S5_, Package() { SLP_TYPa, SLP_TYPb, ... })`.
+fn find_s5_sleep_type() -> Option<(u16, u16)> {
+    let aml = acpi::dsdt()?.data();
+    let pos = aml.windows(4).position(|w| w == b"_S5_")?;
+    // name may be preceded by root prefix `\`
+    let named = (pos >= 1 && aml[pos - 1] == AML_NAME_OP)
+        || (pos >= 2 && aml[pos - 1] == b'\\' && aml[pos - 2] == AML_NAME_OP);
+    if !named || *aml.get(pos + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+    // package length encoding - bits 6-7 of lead byte give count of following bytes
+    let pkg_len_bytes = (*aml.get(pos + 5)? >> 6) as usize + 1;
+    // skip package length and element count
+    let mut idx = pos + 5 + pkg_len_bytes + 1;
+    let mut element = || -> Option<u16> {
+        let value = match *aml.get(idx)? {
+            AML_BYTE_PREFIX => {
+                idx += 1;
+                *aml.get(idx)?
+            }
+            value @ (AML_ZERO_OP | AML_ONE_OP) => value,
+            _ => return None,
+        };
+        idx += 1;
+        Some(value as u16)
+    };
+    let slp_typ_a = element()?;
+    let slp_typ_b = element()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+
+/// Returns I/O port of PM1 control block, preferring 32-bit FADT field and falling back to its
+/// extended version, if it lives in I/O space.
+fn pm1_control_port(block: u32, extended: Option<GenericAddress>) -> Option<u16> {
+    if block != 0 {
+        return Some(block as u16);
+    }
+    extended
+        .filter(|reg| reg.address_space == AddressSpace::SystemIo)
+        .map(|reg| reg.address as u16)
+}
+
+
+/// Switches the system into ACPI mode, if firmware has not done so yet.
+fn enable_acpi_mode(fadt: &Fadt, pm1a: u16) {
+    if input_word(pm1a) & PM1_SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    output_byte(fadt.smi_command as u16, fadt.acpi_enable);
+    // give firmware some time to do the transition
+    for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+        if input_word(pm1a) & PM1_SCI_EN != 0 {
+            break;
+        }
+        pit::busy_wait_us(1000);
+    }
+}
+
+
+/// Enters ACPI S5 (soft off) sleep state. Returns only if the state could not be entered.
+fn acpi_poweroff() {
+    let (Some(fadt), Some((slp_typ_a, slp_typ_b))) = (acpi::fadt(), find_s5_sleep_type()) else {
+        return;
+    };
+    let Some(pm1a) = pm1_control_port(fadt.pm1a_control_block, fadt.x_pm1a_control_block) else {
+        return;
+    };
+    let pm1b = pm1_control_port(fadt.pm1b_control_block, fadt.x_pm1b_control_block);
+
+    enable_acpi_mode(&fadt, pm1a);
+    output_word(pm1a, (input_word(pm1a) & !(0x7 << PM1_SLP_TYP_SHIFT)) | (slp_typ_a << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN);
+    if let Some(pm1b) = pm1b {
+        output_word(pm1b, (input_word(pm1b) & !(0x7 << PM1_SLP_TYP_SHIFT)) | (slp_typ_b << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN);
+    }
+}
+
+
+/// Turns the machine off using ACPI. Emulator specific shutdown ports are tried if ACPI
+/// shutdown fails, the CPU is halted as the last resort.
+pub fn poweroff() -> ! {
+    x86_64::instructions::interrupts::disable();
+    acpi_poweroff();
+
+    // QEMU isa-debug-exit device, exit status (0x31 << 1) | 1 = 99
+    output_byte(0xf4, 0x31);
+    // hard-wired ACPI ports of QEMU and Bochs/older QEMU
+    for port in [0x604, 0xB004] {
+        output_word(port, 0x2000);
+    }
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+
+/// Writes reset value into FADT reset register. Returns only if the reset did not happen.
+fn acpi_reset() {
+    let Some(fadt) = acpi::fadt() else {
+        return;
+    };
+    let Some(reg) = fadt.reset_register.filter(|_| fadt.flags & Fadt::RESET_REG_SUP != 0) else {
+        return;
+    };
+    match reg.address_space {
+        AddressSpace::SystemIo => output_byte(reg.address as u16, fadt.reset_value),
+        AddressSpace::SystemMemory => unsafe {
+            core::ptr::write_volatile(paging::phys_to_virt(reg.address).as_mut_ptr::<u8>(), fadt.reset_value);
+        },
+        AddressSpace::PciConfig => {
+            // register lives on bus 0, address encodes device, function and offset
+            let (device, function, offset) = ((reg.address >> 32) & 0x1F, (reg.address >> 16) & 0x7, reg.address & 0xFF);
+            let config = 0x8000_0000 | (device << 11) | (function << 8) | (offset & 0xFC);
+            let mut address = x86_64::instructions::port::PortWriteOnly::<u32>::new(PCI_CONFIG_ADDRESS);
+            unsafe { address.write(config as u32) };
+            output_byte(PCI_CONFIG_DATA + (offset & 0x3) as u16, fadt.reset_value);
+        }
+        AddressSpace::Other(_) => return,
+    }
+    // reset may take a moment
+    pit::busy_wait_us(RESET_TIMEOUT_MS * 1000);
+}
+
+
+/// Pulses CPU reset line through the 8042 keyboard controller.
+fn keyboard_controller_reset() {
+    if ps2::reset_cpu() {
+        pit::busy_wait_us(RESET_TIMEOUT_MS * 1000);
+    }
+}
+
+
+/// Restarts the machine using ACPI reset register, falling back to 8042 keyboard controller
+/// reset and finally to a triple fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    acpi_reset();
+    keyboard_controller_reset();
+
+    // load empty IDT, so the next exception cannot be handled and causes triple fault
+    let empty = x86_64::structures::DescriptorTablePointer {
+        limit: 0,
+        base: x86_64::VirtAddr::new(0),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,313 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::port::{input_byte, output_byte};
+use crate::{keyboard, pit, vga_printf};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+/// Status: output buffer full (data for the CPU).
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Status: input buffer full (controller has not taken previous byte yet).
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+const CMD_WRITE_PORT2: u8 = 0xD4;
+/// Pulses output line 0 of the controller, which is wired to CPU reset.
+const CMD_PULSE_RESET: u8 = 0xFE;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// controller configuration byte
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+/// Translation of scancode set 2 into set 1 by the controller.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// device commands and responses
+const DEV_SET_LEDS: u8 = 0xED;
+const DEV_SCANCODE_SET: u8 = 0xF0;
+const DEV_IDENTIFY: u8 = 0xF2;
+const DEV_TYPEMATIC: u8 = 0xF3;
+const DEV_ENABLE_SCANNING: u8 = 0xF4;
+const DEV_DISABLE_SCANNING: u8 = 0xF5;
+const DEV_RESET: u8 = 0xFF;
+const DEV_ACK: u8 = 0xFA;
+const DEV_RESEND: u8 = 0xFE;
+const DEV_SELF_TEST_PASSED: u8 = 0xAA;
+
+/// Keyboard LED bits, as sent with the set LEDs command.
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Delay before a held key starts repeating (in ms).
+const TYPEMATIC_DELAY_MS: u32 = 500;
+/// Repeat rate of a held key (in characters per second).
+const TYPEMATIC_RATE: u32 = 20;
+
+/// How long to wait for controller or device (in ms).
+const TIMEOUT_MS: u32 = 50;
+/// Devices may take much longer to finish their reset (in ms).
+const RESET_TIMEOUT_MS: u32 = 1000;
+
+/// Set when a keyboard was found on the first port and initialised.
+static KEYBOARD_PRESENT: AtomicBool = AtomicBool::new(false);
+
+
+/// Port of the PS/2 controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    First,
+    Second,
+}
+
+
+/// Type of device attached to a port, as reported by the identify command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Device {
+    /// Old AT keyboard, which does not answer identify command.
+    AtKeyboard,
+    Mf2Keyboard,
+    Mouse,
+    ScrollMouse,
+    FiveButtonMouse,
+    Unknown(u8, u8),
+}
+
+
+/// Polls status register until given condition holds. Returns false on timeout.
+fn wait_status(timeout_ms: u32, condition: impl Fn(u8) -> bool) -> bool {
+    // interrupts may be disabled, so time is measured by polling the PIT
+    for _ in 0..timeout_ms * 10 {
+        if condition(input_byte(STATUS_PORT)) {
+            return true;
+        }
+        pit::busy_wait_us(100);
+    }
+    false
+}
+
+
+fn write_command(command: u8) -> bool {
+    wait_status(TIMEOUT_MS, |s| s & STATUS_INPUT_FULL == 0) && {
+        output_byte(COMMAND_PORT, command);
+        true
+    }
+}
+
+
+fn write_data(data: u8) -> bool {
+    wait_status(TIMEOUT_MS, |s| s & STATUS_INPUT_FULL == 0) && {
+        output_byte(DATA_PORT, data);
+        true
+    }
+}
+
+
+fn read_data(timeout_ms: u32) -> Option<u8> {
+    wait_status(timeout_ms, |s| s & STATUS_OUTPUT_FULL != 0).then(|| input_byte(DATA_PORT))
+}
+
+
+/// Discards all data waiting in the output buffer.
+fn flush_output() {
+    for _ in 0..16 {
+        if input_byte(STATUS_PORT) & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        input_byte(DATA_PORT);
+    }
+}
+
+
+/// Returns whether data waits in the controller output buffer.
+pub fn has_data() -> bool {
+    input_byte(STATUS_PORT) & STATUS_OUTPUT_FULL != 0
+}
+
+
+fn read_config() -> Option<u8> {
+    write_command(CMD_READ_CONFIG).then(|| read_data(TIMEOUT_MS)).flatten()
+}
+
+
+fn write_config(config: u8) -> bool {
+    write_command(CMD_WRITE_CONFIG) && write_data(config)
+}
+
+
+/// Sends byte to device on given port and waits for its acknowledgement, resending it if the
+/// device asks for it. Bytes other than responses (e.g. scancodes of keys pressed meanwhile)
+/// are passed to the keyboard driver.
+fn device_command(port: Port, byte: u8) -> bool {
+    for _ in 0..3 {
+        if port == Port::Second && !write_command(CMD_WRITE_PORT2) {
+            return false;
+        }
+        if !write_data(byte) {
+            return false;
+        }
+        loop {
+            match read_data(TIMEOUT_MS) {
+                Some(DEV_ACK) => return true,
+                Some(DEV_RESEND) => break,
+                Some(scancode) if port == Port::First => keyboard::_push_key(scancode),
+                Some(_) => {}
+                None => return false,
+            }
+        }
+    }
+    false
+}
+
+
+/// Resets device on given port. Returns false if there is no device or its self-test failed.
+fn reset_device(port: Port) -> bool {
+    if !device_command(port, DEV_RESET) {
+        return false;
+    }
+    let passed = read_data(RESET_TIMEOUT_MS) == Some(DEV_SELF_TEST_PASSED);
+    // mice also send their ID after reset
+    flush_output();
+    passed
+}
+
+
+/// Identifies device on given port. Scanning has to be disabled, so ID bytes are not mixed
+/// with scancodes.
+fn identify(port: Port) -> Option<Device> {
+    if !device_command(port, DEV_IDENTIFY) {
+        return None;
+    }
+    let device = match (read_data(TIMEOUT_MS), read_data(TIMEOUT_MS)) {
+        (None, _) => Device::AtKeyboard,
+        (Some(0x00), _) => Device::Mouse,
+        (Some(0x03), _) => Device::ScrollMouse,
+        (Some(0x04), _) => Device::FiveButtonMouse,
+        (Some(0xAB), Some(0x41 | 0x83 | 0xC1)) => Device::Mf2Keyboard,
+        (Some(first), second) => Device::Unknown(first, second.unwrap_or(0)),
+    };
+    Some(device)
+}
+
+
+/// Returns typematic byte closest to given delay and repeat rate.
+fn typematic_byte(delay_ms: u32, rate: u32) -> u8 {
+    // delay is 250 ms * (1 + bits 5-6)
+    let delay = (delay_ms / 250).clamp(1, 4) as u8 - 1;
+    // repeat period is (8 + bits 0-2) * 2 ^ bits 3-4 * 4.17 ms
+    let period_us = |value: u32| (8 + (value & 0x7)) * (1 << ((value >> 3) & 0x3)) * 4170;
+    let target_us = 1_000_000 / rate.max(1);
+    let value = (0..32).min_by_key(|&value| period_us(value).abs_diff(target_us)).unwrap();
+    (delay << 5) | value as u8
+}
+
+
+/// Initialises keyboard on the first port - switches it to scancode set 2 (translated into
+/// set 1 by the controller), sets typematic rate and enables scanning.
+fn init_keyboard() -> bool {
+    if !device_command(Port::First, DEV_SCANCODE_SET) || !device_command(Port::First, 2) {
+        vga_printf!("[boot] keyboard does not support scancode set 2\n");
+    }
+    let typematic = typematic_byte(TYPEMATIC_DELAY_MS, TYPEMATIC_RATE);
+    if !device_command(Port::First, DEV_TYPEMATIC) || !device_command(Port::First, typematic) {
+        vga_printf!("[boot] failed to set keyboard typematic rate\n");
+    }
+    device_command(Port::First, DEV_ENABLE_SCANNING)
+}
+
+
+/// Initialises the 8042 PS/2 controller - runs controller and port self-tests, resets and
+/// identifies attached devices and configures the keyboard. Only interrupts of the first port
+/// are enabled in the controller, its IRQ line stays masked until the keyboard driver
+/// registers its handler. Returns false if there is no working controller.
+///
+/// Must be called before the keyboard IRQ handler is registered.
+pub fn init() -> bool {
+    // disable devices, so they cannot send anything during initialisation
+    if !write_command(CMD_DISABLE_PORT1) {
+        return false;
+    }
+    write_command(CMD_DISABLE_PORT2);
+    flush_output();
+
+    let Some(mut config) = read_config() else {
+        return false;
+    };
+    config &= !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ);
+    write_config(config);
+
+    if !write_command(CMD_SELF_TEST) || read_data(TIMEOUT_MS) != Some(SELF_TEST_PASSED) {
+        vga_printf!("[boot] PS/2 controller self-test failed\n");
+        return false;
+    }
+    // self-test may reset the controller
+    write_config(config);
+
+    // second port exists if its clock gets enabled
+    let dual = write_command(CMD_ENABLE_PORT2)
+        && read_config().is_some_and(|c| c & CONFIG_PORT2_CLOCK_DISABLED == 0);
+    write_command(CMD_DISABLE_PORT2);
+
+    let port1 = write_command(CMD_TEST_PORT1) && read_data(TIMEOUT_MS) == Some(PORT_TEST_PASSED);
+    let port2 = dual && write_command(CMD_TEST_PORT2) && read_data(TIMEOUT_MS) == Some(PORT_TEST_PASSED);
+
+    for (port, works, enable) in [(Port::First, port1, CMD_ENABLE_PORT1), (Port::Second, port2, CMD_ENABLE_PORT2)] {
+        if !works {
+            continue;
+        }
+        write_command(enable);
+        let device = if reset_device(port) && device_command(port, DEV_DISABLE_SCANNING) {
+            identify(port)
+        } else {
+            None
+        };
+        match device {
+            Some(device) => vga_printf!("[boot] PS/2 port {:?}: {:?}\n", port, device),
+            None => vga_printf!("[boot] PS/2 port {:?}: no device\n", port),
+        }
+        if port == Port::First && matches!(device, Some(Device::AtKeyboard | Device::Mf2Keyboard)) {
+            KEYBOARD_PRESENT.store(init_keyboard(), Ordering::Relaxed);
+            // lock keys start turned off
+            set_leds(0);
+        }
+    }
+    flush_output();
+
+    // keyboard interrupts with translation, mice are not supported yet
+    write_config((config | CONFIG_PORT1_IRQ | CONFIG_TRANSLATION) & !CONFIG_PORT2_IRQ);
+    true
+}
+
+
+/// Resets the CPU through the controller. Returns false if the command could not be sent,
+/// otherwise the reset line is pulsed and the machine should restart shortly.
+pub fn reset_cpu() -> bool {
+    write_command(CMD_PULSE_RESET)
+}
+
+
+/// Turns keyboard LEDs on or off (combination of `LED_*` bits). Returns false if there is no
+/// keyboard.
+pub fn set_leds(leds: u8) -> bool {
+    if !KEYBOARD_PRESENT.load(Ordering::Relaxed) {
+        return false;
+    }
+    // responses are read here, so keyboard IRQ handler must not take them
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        device_command(Port::First, DEV_SET_LEDS) && device_command(Port::First, leds)
+    })
+}
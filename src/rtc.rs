@@ -0,0 +1,256 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{acpi, cmdline, interrupts, pic::IRQ, timer, vga_printf};
+use crate::port::{input_byte, output_byte};
+
+const CMOS_SELECT: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Bit of register select port disabling NMI while CMOS is accessed.
+const CMOS_NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+/// Status A: update in progress.
+const STATUS_A_UIP: u8 = 1 << 7;
+/// Status B: periodic interrupt enable.
+const STATUS_B_PIE: u8 = 1 << 6;
+/// Status B: values are binary (BCD otherwise).
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Status B: 24 hour mode (12 hour mode otherwise).
+const STATUS_B_24H: u8 = 1 << 1;
+/// Hour register bit marking PM in 12 hour mode.
+const HOUR_PM: u8 = 0x80;
+
+/// Base frequency of RTC periodic interrupt divider (in Hz).
+const PERIODIC_BASE_FREQUENCY: u32 = 32768;
+
+/// Unix time (in s) at which uptime was zero, derived from RTC at `init`.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+/// Number of RTC periodic interrupts.
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+
+/// Calendar date and time (UTC, as kept by the RTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns number of seconds since 1970-01-01 00:00:00.
+    pub fn to_unix(self) -> u64 {
+        days_from_civil(self.year as i64, self.month as i64, self.day as i64) as u64 * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// Creates date and time from number of seconds since 1970-01-01 00:00:00.
+    pub fn from_unix(time: u64) -> Self {
+        let (year, month, day) = civil_from_days((time / 86400) as i64);
+        let secs = time % 86400;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+
+/// Returns number of days since 1970-01-01 of given date of proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // shift year start to March, so leap day is the last day of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+
+/// Returns date (year, month, day) of given number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+
+/// Reads CMOS register with NMI disabled, NMI is enabled again afterwards.
+fn cmos_read(reg: u8) -> u8 {
+    output_byte(CMOS_SELECT, CMOS_NMI_DISABLE | reg);
+    let value = input_byte(CMOS_DATA);
+    output_byte(CMOS_SELECT, reg);
+    value
+}
+
+
+/// Writes CMOS register with NMI disabled, NMI is enabled again afterwards.
+fn cmos_write(reg: u8, value: u8) {
+    output_byte(CMOS_SELECT, CMOS_NMI_DISABLE | reg);
+    output_byte(CMOS_DATA, value);
+    output_byte(CMOS_SELECT, reg);
+}
+
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+
+/// Raw values of time registers: seconds, minutes, hours, day, month, year, century.
+type RawTime = [u8; 7];
+
+fn read_raw(century_reg: u8) -> RawTime {
+    // values are inconsistent while RTC updates them
+    while cmos_read(REG_STATUS_A) & STATUS_A_UIP != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        cmos_read(REG_SECONDS),
+        cmos_read(REG_MINUTES),
+        cmos_read(REG_HOURS),
+        cmos_read(REG_DAY),
+        cmos_read(REG_MONTH),
+        cmos_read(REG_YEAR),
+        if century_reg != 0 { cmos_read(century_reg) } else { 0 },
+    ]
+}
+
+
+/// Reads current date and time from the RTC.
+pub fn read() -> DateTime {
+    // century register is optional, its location is given by FADT
+    let century_reg = acpi::fadt().map(|fadt| fadt.century).unwrap_or(0);
+    let raw = x86_64::instructions::interrupts::without_interrupts(|| {
+        // read until two consecutive reads match, so update could not happen in between
+        let mut last = read_raw(century_reg);
+        loop {
+            let current = read_raw(century_reg);
+            if current == last {
+                break current;
+            }
+            last = current;
+        }
+    });
+    let status_b = x86_64::instructions::interrupts::without_interrupts(|| cmos_read(REG_STATUS_B));
+
+    let [mut second, mut minute, hour_raw, mut day, mut month, mut year, mut century] = raw;
+    let pm = hour_raw & HOUR_PM != 0;
+    let mut hour = hour_raw & !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+        century = bcd_to_binary(century);
+    }
+    if status_b & STATUS_B_24H == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour = (hour % 12) + if pm { 12 } else { 0 };
+    }
+    let year = if century != 0 {
+        century as u16 * 100 + year as u16
+    } else {
+        // without century register, assume 21st century
+        2000 + year as u16
+    };
+    DateTime { year, month, day, hour, minute, second }
+}
+
+
+/// Reads the RTC and derives kernel wall-clock from it. Periodic RTC interrupt is enabled, if
+/// requested by `rtc_hz=` command line option. Must be called after the timer is started.
+pub fn init() {
+    let now = read();
+    BOOT_TIME.store(now.to_unix().saturating_sub(timer::uptime_ns() / 1_000_000_000), Ordering::Relaxed);
+
+    if let Some(hz) = cmdline::option("rtc_hz") {
+        match hz.parse::<u32>() {
+            Ok(hz) if enable_periodic_interrupt(hz) => {}
+            _ => vga_printf!("[boot] invalid rtc_hz option (expected power of 2 between 2 and 8192)\n"),
+        }
+    }
+}
+
+
+/// Returns current wall-clock time as number of seconds since 1970-01-01 00:00:00 UTC.
+pub fn unix_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + timer::uptime_ns() / 1_000_000_000
+}
+
+
+/// Returns current wall-clock date and time (UTC).
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_time())
+}
+
+
+/// Enables RTC periodic interrupt with given frequency, which has to be power of 2 between
+/// 2 and 8192 Hz. Returns false if the frequency is not supported.
+pub fn enable_periodic_interrupt(frequency: u32) -> bool {
+    if !frequency.is_power_of_two() || !(2..=8192).contains(&frequency) {
+        return false;
+    }
+    // frequency = 32768 >> (rate - 1)
+    let rate = (PERIODIC_BASE_FREQUENCY / frequency).trailing_zeros() as u8 + 1;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let status_a = cmos_read(REG_STATUS_A);
+        cmos_write(REG_STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = cmos_read(REG_STATUS_B);
+        cmos_write(REG_STATUS_B, status_b | STATUS_B_PIE);
+        // discard pending interrupt flags
+        cmos_read(REG_STATUS_C);
+    });
+    interrupts::register_irq(IRQ::CMOSClock, rtc_interrupt)
+}
+
+
+fn rtc_interrupt() {
+    // RTC does not raise another interrupt until register C is read
+    cmos_read(REG_STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+
+/// Returns number of RTC periodic interrupts so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
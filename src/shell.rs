@@ -1,7 +1,8 @@
 // src/shell.rs
-use alloc::{string::ToString, string::String, vec::Vec};
+use alloc::{string::String, vec::Vec};
+use crate::asyn::StreamExt;
 use crate::{
-    disk, keyboard::{self, Key, KeyState}, vga::{vga_clear_screen, vga_print, vga_print_char, vga_set_foreground, VgaTextModeColor}, vga_printf, MemoryMapEntry, MemoryMapType, Multiboot2, Tag
+    allocator, disk, frame, paging, keyboard::{self, Key, KeyState}, vga::{vga_clear_screen, vga_print, vga_print_char, vga_set_foreground, VgaTextModeColor}, vga_printf, MemoryMapEntry, MemoryMapType, Tag
 };
 
 pub struct Shell {
@@ -10,6 +11,8 @@ pub struct Shell {
     history_index: usize,
     last_key: Option<Key>,
     repeat_counter: u8,
+    /// Number of lost scancodes the user was last warned about.
+    dropped_scancodes: u64,
 }
 
 impl Shell {
@@ -20,22 +23,32 @@ impl Shell {
             history_index: 0,
             last_key: None,
             repeat_counter: 0,
+            dropped_scancodes: 0,
         }
     }
 
-    pub fn run(&mut self) {
+    /// Reads and executes commands, waiting for key events asynchronously.
+    pub async fn run(&mut self) {
         self.show_prompt();
 
-        loop {
-            if keyboard::has_key_event() {
-                if let Some(key_event) = keyboard::translate_key() {
-                    // Only process key presses (not releases)
-                    if key_event.state {
-                        self.process_key(key_event.key);
-                    }
-                }
+        let mut keys = keyboard::KeyStream::new();
+        while let Some(key_event) = keys.next().await {
+            // Only process key presses (not releases)
+            if key_event.state {
+                self.process_key(key_event.key);
             }
-            x86_64::instructions::hlt();
+            self.check_dropped_scancodes();
+        }
+    }
+
+    /// Warns when key events were lost because the scancode queue was full.
+    fn check_dropped_scancodes(&mut self) {
+        let dropped = keyboard::dropped_scancodes();
+        if dropped > self.dropped_scancodes {
+            vga_printf!("\n[keyboard] {} scancodes lost (queue full)\n", dropped - self.dropped_scancodes);
+            self.dropped_scancodes = dropped;
+            self.show_prompt();
+            vga_print(&self.buffer);
         }
     }
 
@@ -46,8 +59,8 @@ impl Shell {
     fn process_key(&mut self, key: Key) {
         match key {
             Key::Char(c) => {
-                // Only process printable ASCII characters
-                if c.is_ascii_graphic() || c == b' ' {
+                // Only process printable characters (ASCII and code page 437 ones, incl. section sign)
+                if c.is_ascii_graphic() || c == b' ' || c >= 0x80 || c == 0x15 {
                     self.handle_char(c);
                 }
             },
@@ -55,6 +68,8 @@ impl Shell {
             Key::Enter => self.handle_enter(),
             Key::Up => self.handle_up_arrow(),
             Key::Down => self.handle_down_arrow(),
+            Key::Control(0x03) => self.handle_cancel(),  // Ctrl+C
+            Key::Control(0x0C) => self.handle_clear(),   // Ctrl+L
             _ => {} // Ignore other keys
         }
     }
@@ -78,7 +93,8 @@ impl Shell {
         vga_print_char(b'\n');
         
         if !self.buffer.is_empty() {
-            let cmd = String::from_utf8_lossy(&self.buffer).to_string();
+            // buffer holds code page 437 characters, each is kept as single char
+            let cmd: String = self.buffer.iter().map(|&c| c as char).collect();
             self.process_command(&cmd);
             self.add_to_history(cmd);
         }
@@ -87,6 +103,21 @@ impl Shell {
         self.show_prompt();
     }
 
+    fn handle_cancel(&mut self) {
+        // Discard current line and start a new one
+        vga_print(b"^C\n");
+        self.buffer.clear();
+        self.history_index = self.command_history.len();
+        self.show_prompt();
+    }
+
+    fn handle_clear(&mut self) {
+        // Clear screen, keeping the line being typed
+        vga_clear_screen();
+        self.show_prompt();
+        vga_print(&self.buffer);
+    }
+
     fn handle_up_arrow(&mut self) {
         if !self.command_history.is_empty() {
             if self.history_index > 0 {
@@ -98,7 +129,7 @@ impl Shell {
                 let cmd = &self.command_history[self.history_index];
                 
                 // Set buffer and display the command
-                self.buffer = cmd.as_bytes().to_vec();
+                self.buffer = cmd.chars().map(|c| c as u8).collect();
                 vga_print(&self.buffer);
             }
         }
@@ -115,7 +146,7 @@ impl Shell {
                 let cmd = &self.command_history[self.history_index];
                 
                 // Set buffer and display the command
-                self.buffer = cmd.as_bytes().to_vec();
+                self.buffer = cmd.chars().map(|c| c as u8).collect();
                 vga_print(&self.buffer);
             } else if self.history_index == self.command_history.len() - 1 {
                 // Clear the line if we're at the end of history
@@ -150,7 +181,17 @@ impl Shell {
             "help" => self.show_help(),
             "clear" => self.clear_screen(),
             "poweroff" => self.poweroff(),
+            "reboot" => self.reboot(),
+            "uptime" => self.show_uptime(),
+            "date" => self.show_date(),
             "multiboot" => self.show_multiboot_info(),
+            "slabinfo" => self.show_slab_info(),
+            "mem" | "free" => self.show_memory_info(),
+            "vmmap" => self.show_vmmap(),
+            "acpi" => self.show_acpi_tables(),
+            "keymap" => self.show_keymaps(),
+            cmd if cmd.starts_with("keymap ") => self.set_keymap(cmd[7..].trim()),
+            cmd if cmd.starts_with("vmmap ") => self.translate_address(&cmd[6..]),
             cmd if cmd.starts_with("echo ") => self.echo(&cmd[5..]),
             cmd if cmd.starts_with("write ") => self.write_disk(&cmd[6..]),
             cmd if cmd.starts_with("read ") => self.read_disk(&cmd[5..]),
@@ -373,24 +414,12 @@ impl Shell {
 
     fn poweroff(&self) {
         vga_print(b"Shutting down...\n");
-        
-        // Method 1: QEMU shutdown with exit code
-        unsafe {
-            // Writing to port 0xf4 will make QEMU exit with status (0x31 << 1) | 1 = 99
-            x86_64::instructions::port::Port::new(0xf4).write(0x31 as u8);
-        }
-        
-        // If QEMU didn't exit, try ACPI shutdown
-        unsafe {
-            for port in [0x604, 0xB004].iter() {  // Try different ACPI ports
-                x86_64::instructions::port::Port::new(*port).write(0x2000 as u16);
-            }
-        }
-        
-        // If still running, halt the CPU
-        loop {
-            x86_64::instructions::hlt();
-        }
+        crate::power::poweroff();
+    }
+
+    fn reboot(&self) {
+        vga_print(b"Rebooting...\n");
+        crate::power::reboot();
     }
 
     fn show_help(&self) {
@@ -399,9 +428,18 @@ impl Shell {
         vga_print(b"- echo <text>: Print text\n");
         vga_print(b"- clear: Clear screen\n");
         vga_print(b"- multiboot: Display multiboot information\n");
+        vga_print(b"- slabinfo: Display usage of heap size classes\n");
+        vga_print(b"- mem, free: Display physical memory and heap usage\n");
+        vga_print(b"- vmmap [address]: Display virtual memory map or translate given address\n");
+        vga_print(b"- acpi: List ACPI tables\n");
+        vga_print(b"- uptime: Display time since boot\n");
+        vga_print(b"- date: Display current date and time\n");
+        vga_print(b"- keymap [name]: List keyboard layouts or switch to given one\n");
         vga_print(b"- poweroff: Turn off\n");
+        vga_print(b"- reboot: Restart the machine\n");
         vga_print(b"- read <address> <count>: Loads data from disk at given address and prints count bytes\n");
         vga_print(b"- write <address> <data>: Writes data into disk starting at given sector address\n");
+        vga_print(b"Ctrl+C discards the current line, Ctrl+L clears the screen\n");
         //TODO: add multiboot info if works
     }
 
@@ -421,7 +459,7 @@ impl Shell {
     }
 
     fn echo(&self, text: &str) {
-        vga_print(text.as_bytes());
+        text.chars().for_each(|c| vga_print_char(c as u8));
         vga_print(b"\n");
     }
 
@@ -431,14 +469,177 @@ impl Shell {
         vga_print(b"\n");
     }
 
+    fn show_memory_info(&self) {
+        let stats = allocator::memory_stats();
+        let frame_kib = |frames: usize| frames as u64 * frame::FRAME_SIZE / 1024;
+        vga_set_foreground(VgaTextModeColor::LightYellow);
+        vga_print(b"Physical memory:\n");
+        vga_set_foreground(VgaTextModeColor::White);
+        vga_printf!("  total      : {} KiB\n", stats.total_physical / 1024);
+        vga_printf!("  usable     : {} KiB\n", stats.usable_physical / 1024);
+        vga_printf!(
+            "  frames     : {} usable ({} KiB), {} allocated ({} KiB), {} free ({} KiB)\n",
+            stats.usable_frames, frame_kib(stats.usable_frames),
+            stats.allocated_frames, frame_kib(stats.allocated_frames),
+            stats.free_frames, frame_kib(stats.free_frames)
+        );
+        vga_set_foreground(VgaTextModeColor::LightYellow);
+        vga_print(b"Kernel heap:\n");
+        vga_set_foreground(VgaTextModeColor::White);
+        let heap = stats.heap;
+        vga_printf!("  size       : {} KiB (limit {} KiB)\n", heap.size / 1024, heap.limit / 1024);
+        vga_printf!("  used       : {} B\n", heap.used);
+        vga_printf!("  free       : {} B\n", heap.free);
+        vga_printf!("  largest    : {} B free block\n", heap.largest_free_block);
+        vga_printf!(
+            "  allocations: {} active, {} total, {} failed\n",
+            heap.allocations, heap.total_allocs, heap.failed_allocs
+        );
+    }
+
+    fn show_vmmap(&self) {
+        let mapper = paging::get_page_mapper(None);
+        vga_set_foreground(VgaTextModeColor::LightYellow);
+        vga_print(b"virtual range                       -> physical   perms page size\n");
+        vga_set_foreground(VgaTextModeColor::White);
+        paging::print_mappings(&mapper);
+    }
+
+    fn translate_address(&self, args: &str) {
+        use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+        let arg = args.trim();
+        let addr = match u64::from_str_radix(arg.trim_start_matches("0x"), 16) {
+            Ok(val) => val,
+            Err(_) => {
+                vga_printf!("Invalid address! (expected hexadecimal number)\n");
+                return;
+            }
+        };
+        let virt = match x86_64::VirtAddr::try_new(addr) {
+            Ok(v) => v,
+            Err(_) => {
+                vga_printf!("{:#x} is not a canonical address\n", addr);
+                return;
+            }
+        };
+        let mapper = paging::get_page_mapper(None);
+        match mapper.translate(virt) {
+            TranslateResult::Mapped { frame, offset, flags } => {
+                vga_printf!(
+                    "{:#x} -> {:#x} ({} KiB page at {:#x}), flags {:?}\n",
+                    addr, frame.start_address().as_u64() + offset, frame.size() / 1024,
+                    frame.start_address().as_u64(), flags
+                );
+            },
+            TranslateResult::NotMapped => vga_printf!("{:#x} is not mapped\n", addr),
+            TranslateResult::InvalidFrameAddress(a) => {
+                vga_printf!("{:#x} maps to invalid frame address {:?}\n", addr, a)
+            },
+        }
+    }
+
+    fn show_acpi_tables(&self) {
+        use crate::acpi::{self, MadtEntry};
+        let rsdp = match acpi::rsdp() {
+            Some(rsdp) => rsdp,
+            None => {
+                vga_print(b"No valid ACPI RSDP found\n");
+                return;
+            }
+        };
+        vga_printf!(
+            "RSDP revision {}, OEM {}, {} at {:#x}\n",
+            rsdp.revision, acpi::ascii(&rsdp.oem_id), if rsdp.xsdt { "XSDT" } else { "RSDT" }, rsdp.root_addr
+        );
+        vga_set_foreground(VgaTextModeColor::LightYellow);
+        vga_print(b"sig  rev  length  OEM     OEM table  checksum\n");
+        vga_set_foreground(VgaTextModeColor::White);
+        let tables = acpi::root_table().into_iter().chain(acpi::tables()).chain(acpi::dsdt());
+        for table in tables {
+            let (revision, length) = (table.revision, table.length);
+            vga_printf!(
+                "{:<4} {:>3}  {:>6}  {:<6}  {:<9}  {}\n",
+                acpi::ascii(&table.signature), revision, length,
+                acpi::ascii(&table.oem_id), acpi::ascii(&table.oem_table_id),
+                if table.is_valid() { "ok" } else { "INVALID" }
+            );
+        }
+        if let Some(madt) = acpi::madt() {
+            let cpus = madt.entries().filter(|e| matches!(e, MadtEntry::LocalApic { .. })).count();
+            let io_apics = madt.entries().filter(|e| matches!(e, MadtEntry::IoApic { .. })).count();
+            vga_printf!("MADT: {} CPU(s), {} I/O APIC(s), local APIC at {:#x}\n", cpus, io_apics, madt.local_apic_addr());
+        }
+        if let Some(hpet) = acpi::hpet() {
+            vga_printf!("HPET: registers at {:#x}, minimum tick {}\n", hpet.base_address.address, hpet.minimum_tick);
+        }
+        if let Some(mcfg) = acpi::mcfg() {
+            for entry in mcfg.entries() {
+                vga_printf!(
+                    "MCFG: segment {} buses {}-{} at {:#x}\n",
+                    entry.segment_group, entry.start_bus, entry.end_bus, entry.base_address
+                );
+            }
+        }
+    }
+
+    fn show_uptime(&self) {
+        let ms = crate::timer::uptime_ms();
+        let secs = ms / 1000;
+        vga_printf!(
+            "up {}:{:02}:{:02}.{:03} ({} ticks at {} Hz, {} clock source)\n",
+            secs / 3600, secs / 60 % 60, secs % 60, ms % 1000,
+            crate::timer::ticks(), crate::timer::frequency(), crate::clock::source().name()
+        );
+    }
+
+    fn show_keymaps(&self) {
+        let current = crate::keymap::current().name;
+        for layout in crate::keymap::LAYOUTS.iter() {
+            let mark = if layout.name == current { '*' } else { ' ' };
+            vga_printf!("{} {:<4} {}\n", mark, layout.name, layout.description);
+        }
+    }
+
+    fn set_keymap(&self, name: &str) {
+        if crate::keymap::set_layout(name) {
+            vga_printf!("Keyboard layout set to {}\n", crate::keymap::current().description);
+        } else {
+            vga_printf!("Unknown keyboard layout: {}\n", name);
+        }
+    }
+
+    fn show_date(&self) {
+        use crate::rtc;
+        vga_printf!("{} UTC\n", rtc::now());
+        vga_printf!("RTC: {} UTC\n", rtc::read());
+        let ticks = rtc::periodic_ticks();
+        if ticks != 0 {
+            vga_printf!("RTC periodic interrupts: {}\n", ticks);
+        }
+    }
+
+    fn show_slab_info(&self) {
+        vga_set_foreground(VgaTextModeColor::LightYellow);
+        vga_print(b" size  pages  in use  capacity  total allocs\n");
+        vga_set_foreground(VgaTextModeColor::White);
+        for class in allocator::size_class_stats() {
+            vga_printf!(
+                "{:>5}  {:>5}  {:>6}  {:>8}  {:>12}\n",
+                class.size, class.pages, class.in_use, class.capacity, class.total_allocs
+            );
+        }
+        let heap = allocator::heap_stats();
+        vga_printf!("large allocations: {} ({} pages)\n", heap.large_allocs, heap.large_pages);
+    }
+
     fn show_multiboot_info(&self) {
         // Get the multiboot information (same way as in lib.rs)
-        let mb_info = unsafe {
-            if crate::multiboot::MULTIBOOT_INFO_ADDR == 0 {
+        let mb_info = match crate::multiboot::boot_info() {
+            Some(mbi) => mbi,
+            None => {
                 vga_print(b"No multiboot information available\n");
                 return;
             }
-            Multiboot2::from_ptr(crate::multiboot::MULTIBOOT_INFO_ADDR as *const u32)
         };
         
         vga_print(b"Multiboot Information:\n");
@@ -0,0 +1,94 @@
+use x86_64::structures::paging as Paging;
+use x86_64::structures::paging::Mapper;
+use x86_64::VirtAddr;
+
+use crate::{frame, paging};
+
+unsafe extern "C" {
+    // defined in boot/boot.S
+    static boot_stack_guard: u8;
+    static stack_start: u8;
+    static double_fault_stack_guard: u8;
+    static double_fault_stack_top: u8;
+    static privilege_stack_guard: u8;
+    static privilege_stack_top: u8;
+}
+
+
+/// Kernel stack with an unmapped guard page right below it.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    /// Name used in diagnostics.
+    pub name: &'static str,
+    /// Start address of the guard page.
+    pub guard: u64,
+    /// Top of the stack (stack grows down from here towards the guard page).
+    pub top: u64,
+}
+
+impl KernelStack {
+    /// Returns whether given address lies within guard page of this stack.
+    pub fn guard_contains(&self, addr: u64) -> bool {
+        addr >= self.guard && addr < self.guard + frame::FRAME_SIZE
+    }
+
+    /// Returns size of the usable stack in bytes.
+    pub fn size(&self) -> u64 {
+        self.top - self.guard - frame::FRAME_SIZE
+    }
+}
+
+
+/// Returns all kernel stacks.
+pub fn kernel_stacks() -> [KernelStack; 3] {
+    [
+        KernelStack {
+            name: "boot",
+            guard: &raw const boot_stack_guard as u64,
+            top: &raw const stack_start as u64,
+        },
+        KernelStack {
+            name: "double fault",
+            guard: &raw const double_fault_stack_guard as u64,
+            top: &raw const double_fault_stack_top as u64,
+        },
+        KernelStack {
+            name: "privilege",
+            guard: &raw const privilege_stack_guard as u64,
+            top: &raw const privilege_stack_top as u64,
+        },
+    ]
+}
+
+
+/// Returns top of the stack used by double fault handler.
+pub fn double_fault_stack_pointer() -> VirtAddr {
+    VirtAddr::new(&raw const double_fault_stack_top as u64)
+}
+
+
+/// Returns top of the stack used when an interrupt arrives while running in ring 3.
+pub fn privilege_stack_pointer() -> VirtAddr {
+    VirtAddr::new(&raw const privilege_stack_top as u64)
+}
+
+
+/// Returns kernel stack, whose guard page contains given address.
+pub fn find_overflowed_stack(addr: u64) -> Option<KernelStack> {
+    kernel_stacks().into_iter().find(|s| s.guard_contains(addr))
+}
+
+
+/// Unmaps guard pages of all kernel stacks. Must be called after the kernel is remapped with
+/// 4 KiB pages (see `paging::remap_kernel`).
+pub fn protect_kernel_stacks() {
+    let mut mapper = paging::get_page_mapper(None);
+    for stack in kernel_stacks() {
+        let page = Paging::Page::<Paging::Size4KiB>::containing_address(VirtAddr::new(stack.guard));
+        // frame belongs to the kernel image, so it is not returned to the frame allocator
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(e) => panic!("failed to unmap guard page of {} stack: {:?}", stack.name, e),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::{apic, asyn, clock, cmdline, interrupts, pic::IRQ, pit, vga_printf};
+
+/// Default frequency of timer interrupts (in Hz), can be changed by `timer_hz=` command line
+/// option.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+const MIN_FREQUENCY: u32 = 20;
+const MAX_FREQUENCY: u32 = 10_000;
+
+/// Number of timer interrupts since `init`.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Configured frequency of timer interrupts (in Hz).
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+/// Actual period between timer interrupts (in ns).
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+
+
+/// Starts periodic timer interrupts - generated by local APIC timer when APIC is used, by the
+/// PIT otherwise.
+pub fn init() {
+    let frequency = match cmdline::option("timer_hz").map(str::parse::<u32>) {
+        Some(Ok(hz)) if (MIN_FREQUENCY..=MAX_FREQUENCY).contains(&hz) => hz,
+        Some(_) => {
+            vga_printf!("[boot] invalid timer_hz option, using {} Hz\n", DEFAULT_FREQUENCY);
+            DEFAULT_FREQUENCY
+        }
+        None => DEFAULT_FREQUENCY,
+    };
+    let tick_ns = if apic::is_enabled() {
+        apic::start_timer(frequency)
+    } else {
+        pit::set_frequency(frequency)
+    };
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    TICK_NS.store(tick_ns, Ordering::Relaxed);
+    if !interrupts::register_irq(IRQ::Timer, timer_tick) {
+        panic!("failed to register timer IRQ handler!");
+    }
+}
+
+
+fn timer_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    asyn::wake_expired_timers(uptime_ns());
+}
+
+
+/// Returns number of timer interrupts since the timer was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+
+/// Returns frequency of timer interrupts (in Hz).
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+
+/// Returns time since the timer was started (in ns), counted in timer ticks.
+pub fn tick_time_ns() -> u64 {
+    ticks() * TICK_NS.load(Ordering::Relaxed)
+}
+
+
+/// Returns monotonic time since boot (in ns), as measured by the selected clock source.
+pub fn uptime_ns() -> u64 {
+    clock::now()
+}
+
+
+/// Returns monotonic time since the timer was started (in ms).
+pub fn uptime_ms() -> u64 {
+    uptime_ns() / 1_000_000
+}
+
+
+/// Sleeps given number of milliseconds, halting the CPU between timer interrupts. Falls back
+/// to busy waiting if interrupts are disabled or the timer is not running.
+#[allow(unused)]
+pub fn sleep_ms(ms: u64) {
+    let tick_ns = TICK_NS.load(Ordering::Relaxed);
+    if tick_ns == 0 || !x86_64::instructions::interrupts::are_enabled() {
+        pit::busy_wait_us(ms * 1000);
+        return;
+    }
+    // round up, so the sleep is never shorter than requested
+    let target = uptime_ns() + ms * 1_000_000 + tick_ns;
+    while uptime_ns() < target {
+        x86_64::instructions::hlt();
+    }
+}
@@ -5,6 +5,12 @@ const VGA_TEXT_MODE_HEIGHT: usize = 25;
 const VGA_TEXT_ADDR: usize = 0xb8000;
 
 
+/// Returns virtual address of VGA text buffer.
+fn vga_buffer() -> usize {
+    crate::paging::phys_to_virt(VGA_TEXT_ADDR as u64).as_u64() as usize
+}
+
+
 #[allow(unused)]
 pub struct VgaTextModeWriter {
     pos_x: usize,
@@ -150,7 +156,7 @@ impl VgaTextModeWriter {
         for y in 0..VGA_TEXT_MODE_HEIGHT {
             for x in 0..VGA_TEXT_MODE_WIDTH {
                 unsafe {
-                    *((VGA_TEXT_ADDR + 2 * x + y * VGA_TEXT_MODE_WIDTH * 2)
+                    *((vga_buffer() + 2 * x + y * VGA_TEXT_MODE_WIDTH * 2)
                         as *mut u16) = 0x0000;
                 }
             }
@@ -165,7 +171,7 @@ impl VgaTextModeWriter {
         }
         for x in 0..VGA_TEXT_MODE_WIDTH {
             unsafe {
-                *((VGA_TEXT_ADDR + 2 * x + row * VGA_TEXT_MODE_WIDTH * 2)
+                *((vga_buffer() + 2 * x + row * VGA_TEXT_MODE_WIDTH * 2)
                     as *mut u16) = 0x0000;
             }
         }
@@ -207,11 +213,11 @@ impl VgaTextModeWriter {
                 for x in 0..VGA_TEXT_MODE_WIDTH {
                     // Copy character from following line into current line.
                     unsafe {
-                        let c = *((VGA_TEXT_ADDR
+                        let c = *((vga_buffer()
                             + (y + count) * VGA_TEXT_MODE_WIDTH * 2
                             + x * 2)
                             as *const u16);
-                        *((VGA_TEXT_ADDR + y * VGA_TEXT_MODE_WIDTH * 2 + x * 2)
+                        *((vga_buffer() + y * VGA_TEXT_MODE_WIDTH * 2 + x * 2)
                             as *mut u16) = c;
                     }
                 }
@@ -235,16 +241,16 @@ impl VgaTextModeWriter {
                     self.pos_x -= 1;
                     let offset = 2 * self.pos_y * VGA_TEXT_MODE_WIDTH + 2 * self.pos_x;
                     unsafe {
-                        *((VGA_TEXT_ADDR + offset) as *mut u8) = b' '; // Clear the character
-                        *((VGA_TEXT_ADDR + offset + 1) as *mut u8) = self.current_attrib;
+                        *((vga_buffer() + offset) as *mut u8) = b' '; // Clear the character
+                        *((vga_buffer() + offset + 1) as *mut u8) = self.current_attrib;
                     }
                 } else if self.pos_y > 0 {
                     self.pos_y -= 1;
                     self.pos_x = VGA_TEXT_MODE_WIDTH - 1;
                     let offset = 2 * self.pos_y * VGA_TEXT_MODE_WIDTH + 2 * self.pos_x;
                     unsafe {
-                        *((VGA_TEXT_ADDR + offset) as *mut u8) = b' '; // Clear the character
-                        *((VGA_TEXT_ADDR + offset + 1) as *mut u8) = self.current_attrib;
+                        *((vga_buffer() + offset) as *mut u8) = b' '; // Clear the character
+                        *((vga_buffer() + offset + 1) as *mut u8) = self.current_attrib;
                     }
                 }
             },
@@ -263,8 +269,8 @@ impl VgaTextModeWriter {
                 }
                 let offset = 2 * self.pos_y * VGA_TEXT_MODE_WIDTH + 2 * self.pos_x;
                 unsafe {
-                    *((VGA_TEXT_ADDR + offset) as *mut u8) = c;
-                    *((VGA_TEXT_ADDR + offset + 1) as *mut u8) = self.current_attrib;
+                    *((vga_buffer() + offset) as *mut u8) = c;
+                    *((vga_buffer() + offset + 1) as *mut u8) = self.current_attrib;
                 }
                 self.pos_x += 1;
             }